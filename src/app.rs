@@ -3,7 +3,7 @@ use log::warn;
 
 use crate::{
     calibration_module::CalibrationModule,
-    camera_module::{CameraModule, Image},
+    camera_module::{CameraModule, Image, PipeSettings},
     spectrum_module::SpectrographModule,
     tracer_module::TracerModule,
 };
@@ -48,18 +48,32 @@ pub enum State {
 }
 
 impl SpeckApp {
-    /// Called once before the first frame.
-    pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
+    /// Called once before the first frame, `pipe` is the source given on the command line.
+    pub fn new(cc: &eframe::CreationContext<'_>, pipe: Option<PipeSettings>) -> Self {
         // This is also where you can customized the look at feel of egui using
         // `cc.egui_ctx.set_visuals` and `cc.egui_ctx.set_fonts`.
 
         let mut app = Self::default();
         if let Some(storage) = cc.storage {
             app.calibration_module = eframe::get_value(storage, "calibration").unwrap_or_default();
+            app.camera_module
+                .stream()
+                .corrections()
+                .set_masks(eframe::get_value(storage, "bad_pixel_masks").unwrap_or_default());
+            app.camera_module
+                .set_profiles(eframe::get_value(storage, "camera_profiles").unwrap_or_default());
+            app.spectrograph_module.set_channel_settings(
+                eframe::get_value(storage, "channel_settings").unwrap_or_default(),
+            );
+            app.spectrograph_module
+                .set_aperture(eframe::get_value(storage, "aperture").unwrap_or_default());
         }
         if app.camera_module.query().is_err() {
             warn!("could not initialise cameras")
         };
+        if let Some(pipe) = pipe {
+            app.camera_module.open_pipe(pipe)
+        }
         app
     }
 }
@@ -68,10 +82,25 @@ impl eframe::App for SpeckApp {
     /// Called by the frame work to save state before shutdown.
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
         eframe::set_value(storage, "calibration", &self.calibration_module);
+        eframe::set_value(
+            storage,
+            "bad_pixel_masks",
+            self.camera_module.stream().corrections().masks(),
+        );
+        eframe::set_value(storage, "camera_profiles", self.camera_module.profiles());
+        eframe::set_value(
+            storage,
+            "channel_settings",
+            self.spectrograph_module.channel_settings(),
+        );
+        eframe::set_value(storage, "aperture", self.spectrograph_module.aperture());
     }
 
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::TopBottomPanel::top("menu").show(ctx, |ui| self.menu(ui));
+        self.camera_module.auto_expose(&mut self.calibration_module);
+        self.camera_module.bracket();
+        self.camera_module.watch_devices();
         match self.state {
             State::CameraView => {
                 self.camera_module
@@ -81,20 +110,17 @@ impl eframe::App for SpeckApp {
                 ctx,
                 &mut self.state,
                 &mut self.calibration_img,
-                self.camera_module.width(),
-                self.camera_module.height(),
+                self.camera_module.stream(),
             ),
             State::GraphView => self.spectrograph_module.display(
                 ctx,
-                self.camera_module.width(),
-                self.camera_module.height(),
+                self.camera_module.stream(),
                 &mut self.calibration_module,
             ),
             State::TracerView => self.tracer_module.display(
                 ctx,
                 &mut self.calibration_module,
-                self.camera_module.width(),
-                self.camera_module.height(),
+                self.camera_module.stream(),
             ),
         }
 
@@ -28,6 +28,15 @@ pub struct CalibrationModule {
     show_generated: Option<u16>,
     #[serde(skip)]
     spectral: Option<SpectralLines>,
+    /// (top, bottom) of the strips beside the spectrum in which only background light
+    /// falls on the sensor, relative to the full frame
+    #[serde(default)]
+    background: Vec<(f32, f32)>,
+    /// dragging over the image marks a background strip instead of drawing a line
+    #[serde(skip)]
+    mark_background: bool,
+    #[serde(skip)]
+    strip_start: Option<f32>,
 }
 
 impl CalibrationModule {
@@ -36,14 +45,13 @@ impl CalibrationModule {
         ctx: &Context,
         main_state: &mut State,
         calibration_image: &mut Option<Image>,
-        width: u32,
-        height: u32,
+        stream: &mut CameraStream,
     ) {
         egui::SidePanel::right("spectrograph_opts").show(ctx, |ui| self.side_panel(ui));
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.vertical_centered(|ui| {
                 if ui.button("take calibration image").clicked() {
-                    if let Some(img) = CameraStream::get_img(width, height) {
+                    if let Some(img) = stream.get_img() {
                         *calibration_image = Some(img);
                     } else {
                         *calibration_image = None;
@@ -60,7 +68,7 @@ impl CalibrationModule {
                                 *main_state = State::CameraView;
                             }
                             if ui.button("take calibration image").clicked() {
-                                if let Some(img) = CameraStream::get_img(width, height) {
+                                if let Some(img) = stream.get_img() {
                                     *calibration_image = Some(img);
                                 } else {
                                     error!("could not take calibration image")
@@ -71,12 +79,13 @@ impl CalibrationModule {
                 }
                 Some(img) => {
                     let aspect_ratio = img.aspect_ratio();
+                    let region = img.region();
                     let texture = img.get_texture(ui);
                     ui.vertical_centered(|ui| {
                         let style = ui.style();
                         Frame::canvas(style).show(ui, |ui| {
                             let (to_screen, response) = draw_texture(texture, ui);
-                            self.main_view(ui, to_screen, aspect_ratio, response);
+                            self.main_view(ui, to_screen, aspect_ratio, region, response);
                         });
                     });
                 }
@@ -98,6 +107,9 @@ impl CalibrationModule {
             angle: 17.5,
             distance_to_sensor: 1.0,
             sensor_width: 0.5,
+            background: Vec::new(),
+            mark_background: false,
+            strip_start: None,
         }
     }
 
@@ -118,6 +130,15 @@ impl CalibrationModule {
         }
     }
 
+    /// calibration with lines that were already measured
+    #[cfg(test)]
+    pub(crate) fn with_lines(lines: Vec<(u16, Line)>) -> Self {
+        Self {
+            lines,
+            ..Self::new()
+        }
+    }
+
     fn add_new_wavelength(&mut self, wavelength: u16) {
         match self.current_line {
             Some(line) => self.lines.push((wavelength, line)),
@@ -171,6 +192,10 @@ impl CalibrationModule {
         Some(lines)
     }
 
+    pub fn background_strips(&self) -> &[(f32, f32)] {
+        &self.background
+    }
+
     pub fn get_line(&mut self, wavelength: f32) -> Option<Line> {
         if self.spectral.is_none() {
             self.generate_regression()?
@@ -188,6 +213,15 @@ const ACTIVE_LINE_STROKE: (f32, Color32) = (5.0, Color32::WHITE);
 const DRAWN_LINE_STROKE: (f32, Color32) = (5.0, Color32::RED);
 const GEN_LINE_STROKE: (f32, Color32) = (2.0, Color32::BLACK);
 const TEXT_COLOR: Color32 = Color32::BLACK;
+const BACKGROUND_FILL: Color32 = Color32::from_rgba_premultiplied(0, 0, 80, 80);
+/// at most one strip on either side of the spectrum
+const MAX_BACKGROUND_STRIPS: usize = 2;
+/// mean squared distance between the measured and the fitted lines, relative to the frame
+/// width, at which the regression stops
+const FIT_TOLERANCE: f32 = 1e-7;
+/// the regression also stops once the distance improved by less than this fraction over a
+/// thousand steps, hand drawn lines are noisy and rarely reach the tolerance
+const FIT_MIN_IMPROVEMENT: f32 = 0.01;
 
 impl CalibrationModule {
     pub fn main_view(
@@ -195,6 +229,7 @@ impl CalibrationModule {
         ui: &mut Ui,
         to_screen: emath::RectTransform,
         aspect_ratio: f32,
+        region: Rect,
         response: Response,
     ) {
         let top_left_screen = to_screen * Pos2 { x: 0.0, y: 0.0 };
@@ -203,11 +238,14 @@ impl CalibrationModule {
                 x: aspect_ratio,
                 y: 1.0,
             };
-        // this allows me to work in normalised coordiantes, [0, 1]x[0, 1]
+        // this allows me to work in normalised coordiantes of the full frame, [0, 1]x[0, 1],
+        // of which a cropped image only shows `region`
         let to_screen = emath::RectTransform::from_to(
-            Rect::from_x_y_ranges(0.0..=1.0, 0.0..=1.0),
+            region,
             Rect::from_min_max(top_left_screen, bottom_right_screen),
         );
+        // lines continue beyond a cropped image
+        ui.set_clip_rect(Rect::from_min_max(top_left_screen, bottom_right_screen));
         let to_picture = to_screen.inverse();
         // Show generated lines if they exist and line_count is set and then skip the rest of this fn
         if let Some(line_count) = self.show_generated.as_ref() {
@@ -216,12 +254,12 @@ impl CalibrationModule {
                     (LARGEST_WAVELENGTH - SMALLEST_WAVELENGTH) as f32 / (*line_count - 1) as f32;
                 for i in 0..*line_count {
                     let wavelength = SMALLEST_WAVELENGTH as f32 + (i as f32 * step);
-                    let screen_points = spectral
-                        .line_with_wavelength(wavelength)
-                        .to_points(to_screen);
-                    ui.painter().line_segment(screen_points, GEN_LINE_STROKE);
+                    let line = spectral.line_with_wavelength(wavelength);
+                    ui.painter()
+                        .line_segment(line.to_points(to_screen), GEN_LINE_STROKE);
+                    let bottom = region.max.y;
                     ui.painter().text(
-                        screen_points[1],
+                        to_screen * Pos2::new(line.cut_with_horizontal(bottom), bottom),
                         Align2::RIGHT_CENTER,
                         wavelength.to_string(),
                         Default::default(),
@@ -230,6 +268,15 @@ impl CalibrationModule {
                 }
             }
         }
+        for (top, bottom) in self.background.iter() {
+            let strip = Rect::from_x_y_ranges(region.x_range(), *top..=*bottom);
+            ui.painter()
+                .rect_filled(to_screen.transform_rect(strip), 0.0, BACKGROUND_FILL);
+        }
+        if self.mark_background {
+            self.mark_strip(ui, to_screen, region, &response);
+            return;
+        }
         // paint lines drawn by the user and its corresponding wavelength
         for (wavelength, line) in self.lines.iter() {
             let points = line.to_points(to_screen);
@@ -307,8 +354,46 @@ impl CalibrationModule {
         }
     }
 
+    /// dragging over the image marks the rows between start and end as background
+    fn mark_strip(
+        &mut self,
+        ui: &mut Ui,
+        to_screen: emath::RectTransform,
+        region: Rect,
+        response: &Response,
+    ) {
+        let pointer_y = || {
+            let pos = response
+                .interact_pointer_pos()
+                .expect("a drag should have a pointer");
+            (to_screen.inverse() * pos).y.clamp(0.0, 1.0)
+        };
+        if response.drag_started() {
+            self.strip_start = Some(pointer_y());
+        } else if let Some(start) = self.strip_start {
+            let end = pointer_y();
+            let (top, bottom) = (start.min(end), start.max(end));
+            if response.drag_released() {
+                self.strip_start = None;
+                if bottom > top {
+                    if self.background.len() == MAX_BACKGROUND_STRIPS {
+                        self.background.remove(0);
+                    }
+                    self.background.push((top, bottom));
+                }
+            } else if response.dragged() {
+                let strip = Rect::from_x_y_ranges(region.x_range(), top..=bottom);
+                ui.painter()
+                    .rect_stroke(to_screen.transform_rect(strip), 0.0, ACTIVE_LINE_STROKE);
+            }
+        }
+    }
+
     pub fn side_panel(&mut self, ui: &mut Ui) {
         ui.label(format!("There are {} lines.", self.lines.len()));
+        if let Some(spectral) = self.spectral.as_ref() {
+            ui.label(spectral.describe());
+        }
         if self.spectral.is_some() {
             match self.show_generated.as_mut() {
                 Some(line_count) => {
@@ -337,6 +422,18 @@ impl CalibrationModule {
             self.current_text = String::new();
         }
 
+        ui.strong("Background");
+        ui.checkbox(&mut self.mark_background, "mark background strips")
+            .on_hover_text(
+                "drag over rows above or below the spectrum that get no light through the slit",
+            );
+        if !self.background.is_empty() {
+            ui.label(format!("{} background strips", self.background.len()));
+            if ui.button("discard background strips").clicked() {
+                self.background.clear();
+            }
+        }
+
         ui.strong("Spectrometer settings");
         ui.label("Angle in degrees");
         ui.add(Slider::new(&mut self.angle, -90.0..=90.0));
@@ -408,6 +505,42 @@ impl SpectralLines {
         })
     }
 
+    /// Builds the lines directly from the geometry of the spectrometer without fitting.
+    /// The offset is the normalised x position of the undeflected ray at the top of the sensor,
+    /// the tilt is how much further right it hits the bottom of the sensor.
+    pub fn from_geometry(
+        grating_const: f32,
+        angle: f32,
+        dist: f32,
+        sensor_width: f32,
+        offset: f32,
+        tilt: f32,
+    ) -> Self {
+        let a = (angle * PI / 360.0).tan();
+        let b = dist / sensor_width;
+        Self {
+            grating_const,
+            top_param: vec![a, b, offset],
+            bottom_param: vec![a, b, offset + tilt],
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let describe_param = |param: &[f32]| {
+            format!(
+                "alpha = {:.2}°, distance / sensor width = {:.3}, offset = {:.3}",
+                param[0].atan() / PI * 360.0,
+                param[1],
+                param[2]
+            )
+        };
+        format!(
+            "top: {}\nbottom: {}",
+            describe_param(&self.top_param),
+            describe_param(&self.bottom_param)
+        )
+    }
+
     pub fn line_with_wavelength(&self, lambda: f32) -> Line {
         let top_normed_x = normed_x(lambda * self.grating_const / 1_000_000.0, &self.top_param);
         let bottom_normed_x = normed_x(
@@ -434,14 +567,27 @@ fn gen_param(lines: Vec<&Line>, rs: &[f32], init_param: Vec<f32>) -> (Vec<f32>,
     let top_problem = FittingProblem {
         data: top_xs.into_iter().zip(rs.iter().cloned()).collect(),
     };
-    let top_param =
-        fitting::search_minimum(top_problem, init_param.clone(), 1_000_000, 0.1, 0.0005);
+    let top_param = fitting::search_minimum(
+        top_problem,
+        init_param.clone(),
+        1_000_000,
+        0.1,
+        FIT_TOLERANCE,
+        FIT_MIN_IMPROVEMENT,
+    );
 
     let bottom_xs = lines.iter().map(|line| line.cut_with_horizontal(1.0));
     let bottom_problem = FittingProblem {
         data: bottom_xs.into_iter().zip(rs.iter().cloned()).collect(),
     };
-    let bottom_param = fitting::search_minimum(bottom_problem, init_param, 1_000_000, 0.1, 0.0005);
+    let bottom_param = fitting::search_minimum(
+        bottom_problem,
+        init_param,
+        1_000_000,
+        0.1,
+        FIT_TOLERANCE,
+        FIT_MIN_IMPROVEMENT,
+    );
 
     (top_param, bottom_param)
 }
@@ -484,3 +630,59 @@ impl Gradient for FittingProblem {
         fitting::scale(grad, 1.0 / self.data.len() as f32)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_module::synthetic_source::tests::{frame, measure_lines, settings};
+
+    #[test]
+    fn regression_recovers_the_synthetic_geometry() {
+        let settings = settings();
+        let img = frame(&settings);
+        let mut calib = CalibrationModule::with_lines(measure_lines(&img));
+        let truth = settings.spectral_lines();
+        // the angle and the offset can largely make up for each other, so the lines are
+        // compared instead of the parameters, also beyond the measured ones
+        let pixel = 1.0 / img.width as f32;
+        for wavelength in [420.0, 436.0, 500.0, 546.0, 600.0, 640.0] {
+            let fitted = calib.get_line(wavelength).unwrap();
+            let expected = truth.line_with_wavelength(wavelength);
+            assert!((fitted.start.0 - expected.start.0).abs() < pixel);
+            assert!((fitted.end.0 - expected.end.0).abs() < pixel);
+        }
+        let spectral = calib.spectral.as_ref().unwrap();
+        for (fitted, expected) in [
+            (&spectral.top_param, &truth.top_param),
+            (&spectral.bottom_param, &truth.bottom_param),
+        ] {
+            assert!((fitted[1] / expected[1] - 1.0).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn regression_of_noisy_lines_stops_near_the_geometry() {
+        let settings = settings();
+        let img = frame(&settings);
+        // hand drawn lines are off by a pixel or two
+        let pixel = 1.0 / img.width as f32;
+        let offsets = [1.5, -1.0, 2.0, -1.5, 0.5, -2.0, 1.0, -0.5];
+        let lines = measure_lines(&img)
+            .into_iter()
+            .enumerate()
+            .map(|(i, (wavelength, mut line))| {
+                line.start.0 += offsets[(2 * i) % offsets.len()] * pixel;
+                line.end.0 += offsets[(2 * i + 1) % offsets.len()] * pixel;
+                (wavelength, line)
+            })
+            .collect();
+        let mut calib = CalibrationModule::with_lines(lines);
+        let truth = settings.spectral_lines();
+        for wavelength in [436.0, 546.0, 600.0] {
+            let fitted = calib.get_line(wavelength).unwrap();
+            let expected = truth.line_with_wavelength(wavelength);
+            assert!((fitted.start.0 - expected.start.0).abs() < 3.0 * pixel);
+            assert!((fitted.end.0 - expected.end.0).abs() < 3.0 * pixel);
+        }
+    }
+}
@@ -1,28 +1,100 @@
-use core::panic;
-use egui::{Context, Slider, Ui};
-use log::{error, warn};
-use std::io::Result;
+use egui::{
+    emath::RectTransform, Context, DragValue, Pos2, Rect, Response, Slider, TextureHandle, Ui,
+};
+use itertools::Itertools;
+use log::{error, info, warn};
+use native_dialog::FileDialog;
+use std::{
+    collections::HashMap,
+    io::Result,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 use v4l::{
     context::Node,
     control,
     format::Colorspace,
     frameinterval::FrameIntervalEnum,
+    framesize::FrameSizeEnum,
     prelude::*,
     video::{capture::Parameters, Capture},
     Control, Format, FourCC, Fraction,
 };
 
+pub mod auto_exposure;
+pub mod bad_pixels;
+pub mod bayer;
+pub mod bracketing;
 pub mod camera_stream;
+pub mod controls;
+pub mod corrections;
+pub mod decode;
+pub mod folder_source;
+pub mod frame_buffer;
+pub mod frame_ranges;
+pub mod frame_source;
 pub mod my_image;
+pub mod pipe_source;
+pub mod profile;
+pub mod recorder;
+pub mod replay_source;
+pub mod roi;
+pub mod sampler;
+pub mod synthetic_source;
+pub mod v4l2_source;
 
+pub use auto_exposure::{AutoExposure, Exposure};
+pub use bayer::CfaPattern;
+pub use bracketing::{BracketAction, Bracketing};
 pub use camera_stream::CameraStream;
-pub use my_image::Image;
+pub use controls::fetch_controls;
+pub use corrections::Corrections;
+pub use folder_source::FolderSource;
+pub use frame_ranges::{IntervalRange, SizeRange};
+pub use frame_source::{FrameSource, SourceKind};
+pub use my_image::{BandRow, DriverStamp, Image};
+pub use pipe_source::{PipeFormat, PipeSettings, PipeSource};
+pub use profile::CameraProfile;
+pub use recorder::Session;
+pub use replay_source::ReplaySource;
+pub use roi::{Crop, Roi};
+pub use sampler::Sampler;
+pub use synthetic_source::{SyntheticSettings, SyntheticSource};
+pub use v4l2_source::V4l2Source;
 
-use crate::app::{draw_texture, State};
+use crate::{
+    app::{draw_texture, State},
+    calibration_module::CalibrationModule,
+    LARGEST_WAVELENGTH, SMALLEST_WAVELENGTH,
+};
+
+/// outline of the region of interest while it is dragged over the frame
+const ROI_STROKE: (f32, egui::Color32) = (2.0, egui::Color32::YELLOW);
+/// how often the V4L2 devices are scanned for a lost camera
+const DEVICE_SCAN_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct CameraModule {
     inner: Option<CamInner>,
     nodes: Vec<Node>,
+    stream: CameraStream,
+    source_kind: SourceKind,
+    synthetic: SyntheticSettings,
+    /// texture of the last displayed frame and its sequence number
+    texture: Option<(u64, TextureHandle)>,
+    /// settings of every camera used so far, keyed by name and bus
+    profiles: HashMap<String, CameraProfile>,
+    /// profile key of the camera that was unplugged while in use
+    lost_camera: Option<String>,
+    last_scan: Option<Instant>,
+    /// roi being edited in the side panel
+    roi_edit: Roi,
+    /// screen position where dragging a roi over the frame started
+    roi_drag: Option<Pos2>,
+    /// speed up of a replayed session compared to the original frame rate
+    replay_speed: f32,
+    /// directory and description of the replayed session
+    replay: Option<(PathBuf, Session)>,
+    pipe: PipeSettings,
 }
 
 impl CameraModule {
@@ -32,12 +104,14 @@ impl CameraModule {
         calibration_image: &mut Option<Image>,
         state: &mut State,
     ) {
-        egui::SidePanel::left("spectrograph_opts").show(ctx, |ui| self.side_panel(ui));
+        egui::SidePanel::left("spectrograph_opts").show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| self.side_panel(ui))
+        });
         egui::CentralPanel::default().show(ctx, |ui| {
-            if CameraStream::is_open() {
+            if self.stream.is_open() {
                 ui.vertical_centered(|ui| {
                     if ui.button("take calibration image").clicked() {
-                        if let Some(img) = CameraStream::get_img(self.width(), self.height()) {
+                        if let Some(img) = self.stream.get_img() {
                             *calibration_image = Some(img);
                             *state = State::Calibration;
                         } else {
@@ -45,19 +119,38 @@ impl CameraModule {
                             error!("could not take calibration image")
                         }
                     }
-                    if let Some(texture) =
-                        CameraStream::get_img_as_texture(ui.ctx(), self.width(), self.height())
-                    {
-                        egui::Frame::canvas(ui.style()).show(ui, |ui| {
-                            draw_texture(&texture, ui);
-                        });
-                        ui.ctx().request_repaint()
+                    if let Some(frame) = self.stream.latest() {
+                        let texture = match self.texture.take() {
+                            Some((sequence, texture)) if sequence == frame.sequence => texture,
+                            _ => ui.ctx().load_texture(
+                                "camera frame",
+                                frame.img.to_color_image(),
+                                egui::TextureFilter::Linear,
+                            ),
+                        };
+                        let selected = egui::Frame::canvas(ui.style())
+                            .show(ui, |ui| {
+                                let (to_screen, response) = draw_texture(&texture, ui);
+                                self.select_roi(&frame.img, to_screen, &response, ui)
+                            })
+                            .inner;
+                        self.texture = Some((frame.sequence, texture));
+                        if let Some(roi) = selected {
+                            self.set_roi(Some(roi))
+                        }
                     }
+                    ui.ctx().request_repaint()
                 });
             } else if self.has_camera() {
                 self.make_stream()
+            } else if let Some(lost) = self.lost_camera.as_ref() {
+                ui.label(format!(
+                    "{} was disconnected, waiting for it to come back",
+                    lost
+                ));
+                ui.ctx().request_repaint()
             } else {
-                ui.label("no active camera");
+                ui.label("no active source");
             }
         });
     }
@@ -68,6 +161,18 @@ impl CameraModule {
         Self {
             inner: None,
             nodes: Vec::new(),
+            stream: CameraStream::default(),
+            source_kind: SourceKind::default(),
+            synthetic: SyntheticSettings::default(),
+            texture: None,
+            profiles: HashMap::new(),
+            lost_camera: None,
+            last_scan: None,
+            roi_edit: Roi::full(0, 0),
+            roi_drag: None,
+            replay_speed: 1.0,
+            replay: None,
+            pipe: PipeSettings::default(),
         }
     }
 
@@ -78,41 +183,260 @@ impl CameraModule {
     }
 
     pub fn make_stream(&mut self) {
-        let camera = &self
-            .inner
-            .as_ref()
-            .expect("module should be initialised")
-            .camera;
-        CameraStream::open_stream(camera)
+        let inner = self.inner.as_ref().expect("module should be initialised");
+        match V4l2Source::new(&inner.camera, inner.demosaic, inner.crop()) {
+            Ok(source) => {
+                self.stream.open(Box::new(source));
+                self.stream.set_exposure(inner.exposure());
+            }
+            Err(err) => error!("Could not open stream:   {}", err),
+        }
+    }
+
+    /// Runs the auto exposure on the newest frame, the peak is searched along the
+    /// calibrated spectral range or in the whole frame if there is no calibration.
+    pub fn auto_expose(&mut self, calib: &mut CalibrationModule) {
+        let inner = match self.inner.as_mut() {
+            Some(inner) if inner.auto_exposure.is_adjusting() => inner,
+            _ => return,
+        };
+        let current = match inner.exposure() {
+            Some(current) => current,
+            None => return,
+        };
+        let frame = match self.stream.latest() {
+            Some(frame) => frame,
+            None => return,
+        };
+        // only frames taken with the current settings tell whether they fit
+        if frame.img.exposure != Some(current) || Some(frame.sequence) <= inner.exposure_checked {
+            return;
+        }
+        inner.exposure_checked = Some(frame.sequence);
+
+        let peak = match calib.get_lines(SMALLEST_WAVELENGTH as f32, LARGEST_WAVELENGTH as f32, 1.0)
+        {
+            Some(lines) => lines
+                .iter()
+                .filter_map(|line| frame.img.max_along_line(line))
+                .max(),
+            None => frame.img.max_sample(),
+        };
+        let peak = match peak {
+            Some(peak) => peak as f32 / u16::MAX as f32,
+            None => return,
+        };
+        let time_limits = match auto_exposure::control_limits(
+            &inner.controls,
+            auto_exposure::CID_EXPOSURE_ABSOLUTE,
+        ) {
+            Some(limits) => limits,
+            None => return,
+        };
+        let gain_limits = auto_exposure::control_limits(&inner.controls, auto_exposure::CID_GAIN);
+        if let Some(next) = inner
+            .auto_exposure
+            .update(peak, current, time_limits, gain_limits)
+        {
+            match inner.apply_exposure(next) {
+                Ok(_) => self.stream.set_exposure(Some(next)),
+                Err(err) => error!("could not set exposure: {}", err),
+            }
+        }
+    }
+
+    /// Moves on to the next exposure of the bracket once a settled frame with the current one
+    /// arrived.
+    pub fn bracket(&mut self) {
+        let inner = match self.inner.as_mut() {
+            Some(inner) if inner.bracketing.is_active() => inner,
+            _ => return,
+        };
+        // the merged spectrum would mix dark subtracted and raw exposures
+        if self.stream.corrections().subtracts_dark() {
+            warn!("a dark frame is subtracted, stopping the bracket");
+            if let Some(base) = inner.bracketing.stop() {
+                inner.stop_bracketing(base, &mut self.stream);
+            }
+            return;
+        }
+        let exposures = match self.stream.bracket() {
+            Some(exposures) if !exposures.is_empty() => exposures,
+            _ => return,
+        };
+        let current = inner.exposure();
+        let next = match exposures
+            .iter()
+            .position(|exposure| Some(*exposure) == current)
+        {
+            Some(index) => {
+                let frame = match self.stream.latest() {
+                    Some(frame) => frame,
+                    None => return,
+                };
+                // the frames right after a change are untagged, so this waits for them to settle
+                if frame.img.exposure != current || Some(frame.sequence) <= inner.exposure_checked {
+                    return;
+                }
+                inner.exposure_checked = Some(frame.sequence);
+                exposures[(index + 1) % exposures.len()]
+            }
+            // the camera is not at a step of the bracket, e.g. because its automatic exposure
+            // was switched on again, so start over and wait on the first step as on any other
+            None => {
+                if current.is_none() {
+                    if let Err(err) = inner.set_manual_exposure() {
+                        error!("could not switch to manual exposure: {}", err);
+                        return;
+                    }
+                }
+                exposures[0]
+            }
+        };
+        match inner.apply_exposure(next) {
+            Ok(_) => self.stream.set_exposure(Some(next)),
+            Err(err) => error!("could not set exposure: {}", err),
+        }
+    }
+
+    /// opens the camera and restores its saved profile
+    fn initialise(&mut self, index: usize) -> Result<()> {
+        let mut inner = CamInner::new(index)?;
+        if let Some(profile) = self.profiles.get(&inner.profile_key) {
+            inner.apply_profile(profile);
+        }
+        self.lost_camera = None;
+        self.stream.set_roi(inner.roi);
+        self.inner = Some(inner);
+        Ok(())
+    }
+
+    /// Notices when the active camera disappears and reopens it with its previous profile
+    /// once a device with the same name and bus shows up again. V4L2 has no events for
+    /// this, so the devices are scanned periodically while a camera is missing.
+    pub fn watch_devices(&mut self) {
+        if self
+            .last_scan
+            .map(|last| last.elapsed() < DEVICE_SCAN_INTERVAL)
+            .unwrap_or(false)
+        {
+            return;
+        }
+        self.last_scan = Some(Instant::now());
+
+        if let Some(inner) = self.inner.as_ref() {
+            let unplugged = !Path::new(&format!("/dev/video{}", inner.index)).exists();
+            if unplugged || self.stream.is_lost() {
+                warn!("{} was disconnected", inner.profile_key);
+                self.lost_camera = Some(inner.profile_key.clone());
+                self.store_profile();
+                self.inner = None;
+                self.stream.close();
+            }
+            return;
+        }
+
+        // enumerating the devices is slow, so only look for a camera that went missing
+        let lost = match self.lost_camera.clone() {
+            Some(lost) if self.source_kind == SourceKind::V4l2 => lost,
+            _ => return,
+        };
+        let nodes = v4l::context::enum_devices();
+        let index = nodes.iter().map(|node| node.index()).find(|index| {
+            Device::new(*index)
+                .and_then(|camera| profile::profile_key(&camera))
+                .map(|key| key == lost)
+                .unwrap_or(false)
+        });
+        if !self.nodes.is_empty() {
+            self.nodes = nodes;
+        }
+        if let Some(index) = index {
+            info!("{} is back, reconnecting", lost);
+            match self.initialise(index) {
+                Ok(_) => self.make_stream(),
+                Err(err) => error!("could not reconnect {}: {}", lost, err),
+            }
+        }
+    }
+
+    /// the saved camera profiles including the settings of the current camera
+    pub fn profiles(&mut self) -> &HashMap<String, CameraProfile> {
+        self.store_profile();
+        &self.profiles
+    }
+
+    pub fn set_profiles(&mut self, profiles: HashMap<String, CameraProfile>) {
+        self.profiles = profiles
+    }
+
+    fn store_profile(&mut self) {
+        if let Some(inner) = self.inner.as_ref() {
+            self.profiles
+                .insert(inner.profile_key.clone(), inner.profile());
+        }
     }
 
     pub fn reset(&mut self) {
+        self.store_profile();
         self.nodes = Vec::new();
         self.inner = None;
-        CameraStream::close();
+        self.lost_camera = None;
+        self.stream.set_bracket(None);
+        self.stream.set_roi(None);
+        self.stream.close();
     }
 
     pub fn has_camera(&self) -> bool {
         self.inner.is_some()
     }
 
-    pub fn width(&self) -> u32 {
-        self.inner
-            .as_ref()
-            .expect("inner should be initialised")
-            .width
-    }
-
-    pub fn height(&self) -> u32 {
-        self.inner
-            .as_ref()
-            .expect("inner should be initialised")
-            .height
+    pub fn stream(&mut self) -> &mut CameraStream {
+        &mut self.stream
     }
 }
 impl CameraModule {
     pub fn side_panel(&mut self, ui: &mut Ui) {
         ui.heading("Camera Module");
+        egui::ComboBox::from_label("source")
+            .selected_text(self.source_kind.to_string())
+            .show_ui(ui, |ui| {
+                for kind in SourceKind::ALL {
+                    if ui
+                        .selectable_label(self.source_kind == kind, kind.to_string())
+                        .clicked()
+                        && self.source_kind != kind
+                    {
+                        self.reset();
+                        self.source_kind = kind;
+                    }
+                }
+            });
+        match self.source_kind {
+            SourceKind::V4l2 => self.v4l2_panel(ui),
+            SourceKind::ImageFolder => self.folder_panel(ui),
+            SourceKind::Synthetic => self.synthetic_panel(ui),
+            SourceKind::Replay => self.replay_panel(ui),
+            SourceKind::Pipe => self.pipe_panel(ui),
+        }
+        if ui.button("reset camera").clicked() {
+            self.reset()
+        }
+        if self.stream.is_open() {
+            ui.label(format!(
+                "frames dropped by the driver: {}",
+                self.stream.dropped_frames()
+            ));
+        }
+        ui.separator();
+        self.roi_panel(ui);
+        ui.separator();
+        self.recording_panel(ui);
+        ui.separator();
+        self.stream.corrections().ui(ui);
+    }
+
+    fn v4l2_panel(&mut self, ui: &mut Ui) {
         match (!self.nodes.is_empty(), self.inner.is_some()) {
             (false, false) => {
                 if ui.button("get cameras").clicked() {
@@ -123,84 +447,365 @@ impl CameraModule {
                 }
             }
             (true, false) => {
+                let mut selected = None;
                 for node in self.nodes.iter() {
                     match node.name() {
                         Some(name) => {
                             ui.label(name);
                             if ui.button("initialise").clicked() {
-                                match CamInner::new(node.index()) {
-                                    Ok(inner) => self.inner = Some(inner),
-                                    Err(err) => error!("{}", err),
-                                }
+                                selected = Some(node.index())
                             }
                         }
                         None => warn!("could not read camera name at idx: {}", node.index()),
                     }
                 }
+                if let Some(index) = selected {
+                    if let Err(err) = self.initialise(index) {
+                        error!("{}", err)
+                    }
+                }
             }
             (true, true) => {
                 self.inner
                     .as_mut()
                     .expect("camera should be initialised")
-                    .update_side_panel(ui);
+                    .update_side_panel(ui, &mut self.stream);
             }
             (false, true) => {
                 unreachable!()
             }
         }
-        if ui.button("reset camera").clicked() {
-            self.reset()
+    }
+
+    fn roi_panel(&mut self, ui: &mut Ui) {
+        ui.strong("Region of interest");
+        let hardware_crop = self
+            .inner
+            .as_ref()
+            .map(|inner| inner.hardware_crop)
+            .unwrap_or(false);
+        match self.stream.roi() {
+            Some(roi) if hardware_crop => ui.label(format!("cropped to {} by the camera", roi)),
+            Some(roi) => ui.label(format!("cropped to {}", roi)),
+            None => ui.label("full frame, drag over the frame to crop it"),
+        };
+        // there is nothing to crop until a frame with pixels arrived
+        let (full_width, full_height) = match self.stream.latest() {
+            Some(frame) => frame.img.full_size(),
+            None => return,
+        };
+        if full_width == 0 || full_height == 0 {
+            return;
+        }
+        if self.roi_edit.width == 0 {
+            self.roi_edit = Roi::full(full_width, full_height);
         }
+        let roi = &mut self.roi_edit;
+        egui::Grid::new("roi").show(ui, |ui| {
+            ui.label("x");
+            ui.add(DragValue::new(&mut roi.x).clamp_range(0..=full_width - 1));
+            ui.label("y");
+            ui.add(DragValue::new(&mut roi.y).clamp_range(0..=full_height - 1));
+            ui.end_row();
+            ui.label("width");
+            ui.add(DragValue::new(&mut roi.width).clamp_range(1..=full_width));
+            ui.label("height");
+            ui.add(DragValue::new(&mut roi.height).clamp_range(1..=full_height));
+            ui.end_row();
+        });
+        ui.horizontal(|ui| {
+            if ui.button("crop").clicked() {
+                match self.roi_edit.clamp(full_width, full_height) {
+                    Some(roi) => self.set_roi(Some(roi)),
+                    None => warn!("the region of interest lies outside of the frame"),
+                }
+            }
+            if ui.button("full frame").clicked() {
+                self.set_roi(None)
+            }
+        });
     }
-}
 
-impl Default for CameraModule {
-    fn default() -> Self {
-        Self::new()
+    fn set_roi(&mut self, roi: Option<Roi>) {
+        if let Some(roi) = roi {
+            self.roi_edit = roi;
+        }
+        match self.inner.as_mut() {
+            Some(inner) => inner.set_roi(roi, &mut self.stream),
+            None => self.stream.set_roi(roi),
+        }
+    }
+
+    /// Lets the user drag a rectangle over the frame, returns it in pixels of the full frame
+    /// once the drag is released.
+    fn select_roi(
+        &mut self,
+        img: &Image,
+        to_screen: RectTransform,
+        response: &Response,
+        ui: &Ui,
+    ) -> Option<Roi> {
+        if response.drag_started() {
+            self.roi_drag = response.interact_pointer_pos();
+        }
+        let start = self.roi_drag?;
+        let end = response.interact_pointer_pos()?;
+        let rect = Rect::from_two_pos(start, end);
+        if response.dragged() {
+            ui.painter().rect_stroke(rect, 0.0, ROI_STROKE);
+            return None;
+        }
+        if !response.drag_released() {
+            return None;
+        }
+        self.roi_drag = None;
+        let (x0, y0) = img.origin();
+        let to_pixel = |pos: Pos2| {
+            let pos = to_screen.inverse() * pos;
+            let x = (pos.x / img.aspect_ratio()).clamp(0.0, 1.0) * img.width as f32;
+            let y = pos.y.clamp(0.0, 1.0) * img.height as f32;
+            (x0 + x.round() as usize, y0 + y.round() as usize)
+        };
+        let (left, top) = to_pixel(rect.min);
+        let (right, bottom) = to_pixel(rect.max);
+        (right > left && bottom > top).then(|| Roi {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        })
+    }
+
+    fn folder_panel(&mut self, ui: &mut Ui) {
+        if ui.button("choose folder").clicked() {
+            if let Some(path) = choose_dir() {
+                match FolderSource::new(path) {
+                    Ok(source) => self.stream.open(Box::new(source)),
+                    Err(err) => error!("could not open image folder: {}", err),
+                }
+            }
+        }
+        if let Some(name) = self.stream.name() {
+            ui.label(name);
+        }
+    }
+
+    fn replay_panel(&mut self, ui: &mut Ui) {
+        ui.add(
+            Slider::new(&mut self.replay_speed, 1.0..=64.0)
+                .logarithmic(true)
+                .text("speed"),
+        );
+        let mut dir = None;
+        ui.horizontal(|ui| {
+            if ui.button("choose session").clicked() {
+                dir = choose_dir();
+            }
+            if let Some((path, _)) = self.replay.as_ref() {
+                if ui.button("replay again").clicked() {
+                    dir = Some(path.clone());
+                }
+            }
+        });
+        if let Some(dir) = dir {
+            match ReplaySource::new(&dir, self.replay_speed) {
+                Ok(source) => {
+                    self.replay = Some((dir, source.session().clone()));
+                    self.stream.open(Box::new(source));
+                }
+                Err(err) => error!("could not open the recorded session: {}", err),
+            }
+        }
+        if let Some(name) = self.stream.name() {
+            ui.label(name);
+        }
+        if let Some(profile) = self
+            .replay
+            .as_ref()
+            .and_then(|(_, session)| session.profile.as_ref())
+        {
+            ui.label(format!(
+                "recorded at {}x{} {}, {} with {} controls set",
+                profile.width,
+                profile.height,
+                FourCC::new(&profile.fourcc),
+                frame_ranges::describe(Fraction::new(profile.interval.0, profile.interval.1)),
+                profile.controls.len()
+            ));
+        }
+    }
+
+    fn pipe_panel(&mut self, ui: &mut Ui) {
+        let pipe = &mut self.pipe;
+        egui::Grid::new("pipe").show(ui, |ui| {
+            ui.label("path");
+            ui.text_edit_singleline(&mut pipe.path)
+                .on_hover_text("named pipe to read from, - for stdin");
+            ui.end_row();
+            ui.label("size");
+            ui.horizontal(|ui| {
+                ui.add(DragValue::new(&mut pipe.width).clamp_range(1..=u16::MAX));
+                ui.label("x");
+                ui.add(DragValue::new(&mut pipe.height).clamp_range(1..=u16::MAX));
+            });
+            ui.end_row();
+            ui.label("format");
+            egui::ComboBox::from_id_source("pipe format")
+                .selected_text(pipe.format.to_string())
+                .show_ui(ui, |ui| {
+                    for format in PipeFormat::ALL {
+                        ui.selectable_value(&mut pipe.format, format, format.to_string());
+                    }
+                });
+            ui.end_row();
+        });
+        if ui.button("open").clicked() {
+            self.open_pipe(self.pipe.clone())
+        }
+        if let Some(name) = self.stream.name() {
+            ui.label(name);
+        }
+    }
+
+    /// reads raw frames from stdin or a named pipe, e.g. as given on the command line
+    pub fn open_pipe(&mut self, settings: PipeSettings) {
+        if self.source_kind != SourceKind::Pipe {
+            self.reset();
+            self.source_kind = SourceKind::Pipe;
+        }
+        match PipeSource::new(settings.clone()) {
+            Ok(source) => self.stream.open(Box::new(source)),
+            Err(err) => error!("could not read from the pipe: {}", err),
+        }
+        self.pipe = settings;
+    }
+
+    fn recording_panel(&mut self, ui: &mut Ui) {
+        ui.strong("Recording");
+        match self.stream.recording() {
+            Some((dir, written, skipped)) => {
+                ui.label(format!(
+                    "recording to {}\n{} frames written",
+                    dir.display(),
+                    written
+                ));
+                if skipped > 0 {
+                    ui.colored_label(
+                        egui::Color32::RED,
+                        format!("{} frames skipped, the disk is too slow", skipped),
+                    );
+                }
+                if ui.button("stop recording").clicked() {
+                    self.stream.stop_recording()
+                }
+            }
+            None => {
+                if ui
+                    .add_enabled(self.stream.is_open(), egui::Button::new("record frames"))
+                    .on_hover_text(
+                        "saves every frame losslessly with its settings to a new folder in the chosen one",
+                    )
+                    .clicked()
+                {
+                    if let Some(dir) = choose_dir() {
+                        self.start_recording(dir)
+                    }
+                }
+            }
+        }
+    }
+
+    /// starts recording to a new session folder in `dir`
+    fn start_recording(&mut self, dir: PathBuf) {
+        let started = chrono::Local::now();
+        let session = Session {
+            source: self.stream.name().unwrap_or_default(),
+            started: started.format("%Y-%m-%d %H:%M:%S").to_string(),
+            profile: self.inner.as_ref().map(|inner| inner.profile()),
+        };
+        let dir = dir.join(format!("session_{}", started.format("%Y_%m_%d_%H_%M_%S")));
+        if let Err(err) = self.stream.start_recording(dir, &session) {
+            error!("could not start recording: {}", err)
+        }
+    }
+
+    fn synthetic_panel(&mut self, ui: &mut Ui) {
+        if self.synthetic.ui(ui) || !self.stream.is_open() {
+            match SyntheticSource::new(self.synthetic.clone()) {
+                Ok(source) => self.stream.open(Box::new(source)),
+                Err(err) => error!("could not create synthetic source: {}", err),
+            }
+        }
     }
 }
 
-pub fn fetch_controls(camera: &Device) -> Result<Vec<(control::Description, Control)>> {
-    let ctrl_description = camera.query_controls()?;
-    let mut controls = Vec::new();
-    for d in ctrl_description {
-        match camera.control(d.id) {
-            Ok(control) => controls.push((d, control)),
-            Err(err) => warn!(
-                "failed to load value for {}, id: {}, type: {}, disregarding it. Err:{}",
-                d.name, d.id, d.typ, err
-            ),
+fn choose_dir() -> Option<PathBuf> {
+    let dialog_result = match home::home_dir() {
+        Some(home) => FileDialog::new().set_location(&home).show_open_single_dir(),
+        None => FileDialog::new().show_open_single_dir(),
+    };
+    match dialog_result {
+        Ok(Some(path)) => Some(path),
+        Ok(None) => {
+            warn!("no path was returned");
+            None
+        }
+        Err(err) => {
+            error!("could not get location, Error: {}", err);
+            None
         }
     }
-    Ok(controls)
 }
 
-pub fn set_control(cam: &Device, ctrl: Control) -> Result<()> {
-    CameraStream::close();
-    cam.set_control(ctrl)
+impl Default for CameraModule {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 struct CamInner {
     camera: Device,
+    /// number of the /dev/video device
+    index: usize,
+    /// identifies the camera in the saved profiles
+    profile_key: String,
     controls: Vec<(control::Description, Control)>,
     color_space: Colorspace,
     fourcc: FourCC,
     width: u32,
     height: u32,
     interval: Fraction,
+    demosaic: bool,
     show_controls: bool,
+    auto_exposure: AutoExposure,
+    bracketing: Bracketing,
+    /// sequence number of the last frame looked at by the auto exposure or bracketing
+    exposure_checked: Option<u64>,
+    size_range: Option<SizeRange>,
+    interval_range: Option<IntervalRange>,
+    /// size and interval in seconds entered for cameras with ranges
+    custom_size: (u32, u32),
+    custom_interval: f64,
+    roi: Option<Roi>,
+    /// the driver crops the frames to the roi
+    hardware_crop: bool,
 }
 
 impl CamInner {
     fn new(index: usize) -> Result<Self> {
         let camera = Device::new(index)?;
-        // let caps = camera.query_caps()?;
+        let profile_key = profile::profile_key(&camera)?;
 
-        let mut formats = camera.enum_formats()?;
-        formats.retain(|f| f.fourcc == FourCC::new(b"RGB3"));
+        let formats = camera.enum_formats()?;
+        let preferred = decode::SUPPORTED_FORMATS.iter().find_map(|repr| {
+            formats
+                .iter()
+                .find(|f| f.fourcc == FourCC::new(repr))
+                .map(|f| f.fourcc)
+        });
         let mut format = camera.format()?;
-        if !formats.is_empty() {
-            format.fourcc = formats[0].fourcc;
+        if let Some(fourcc) = preferred {
+            format.fourcc = fourcc;
             match camera.set_format(&format) {
                 Ok(f) => format = f,
                 Err(err) => return Err(err),
@@ -210,21 +815,253 @@ impl CamInner {
         let controls = fetch_controls(&camera)?;
 
         let param = camera.params()?;
-        Ok(Self {
+        let mut inner = Self {
             camera,
+            index,
+            profile_key,
             controls,
             color_space: format.colorspace,
             fourcc: format.fourcc,
             width: format.width,
             height: format.height,
             interval: param.interval,
+            demosaic: false,
             show_controls: false,
+            auto_exposure: AutoExposure::default(),
+            bracketing: Bracketing::default(),
+            exposure_checked: None,
+            size_range: None,
+            interval_range: None,
+            custom_size: (format.width, format.height),
+            custom_interval: frame_ranges::seconds(param.interval),
+            roi: None,
+            hardware_crop: false,
+        };
+        inner.refresh_ranges();
+        Ok(inner)
+    }
+
+    /// changes the format of the full frame, a crop of the driver is redone afterwards
+    fn set_format(&mut self, format: Format, stream: &mut CameraStream) {
+        stream.close();
+        if self.hardware_crop {
+            roi::hardware_crop(&self.camera, None, &self.full_format());
+            self.hardware_crop = false;
+        }
+        match self.camera.set_format(&format) {
+            Ok(format) => {
+                self.width = format.width;
+                self.height = format.height;
+                self.fourcc = format.fourcc;
+            }
+            Err(err) => error!("{}", err),
+        }
+        // the available frame intervals depend on the size
+        self.refresh_ranges();
+        if self.roi.is_some() {
+            self.hardware_crop = roi::hardware_crop(&self.camera, self.roi, &self.full_format());
+        }
+    }
+
+    /// crops in the driver if it can do so, the stream crops in software otherwise
+    fn set_roi(&mut self, roi: Option<Roi>, stream: &mut CameraStream) {
+        stream.close();
+        self.roi = roi;
+        self.hardware_crop = roi::hardware_crop(&self.camera, roi, &self.full_format());
+        stream.set_roi(roi);
+    }
+
+    fn full_format(&self) -> Format {
+        Format::new(self.width, self.height, self.fourcc)
+    }
+
+    /// where the frames of the driver lie in the full frame
+    fn crop(&self) -> Option<Crop> {
+        let roi = self.roi.filter(|_| self.hardware_crop)?;
+        Some(Crop {
+            x: roi.x,
+            y: roi.y,
+            full_width: self.width as usize,
+            full_height: self.height as usize,
         })
     }
+
+    fn set_interval(&mut self, interval: Fraction, stream: &mut CameraStream) {
+        stream.close();
+        match self.camera.set_params(&Parameters::new(interval)) {
+            Ok(para) => {
+                self.interval = para.interval;
+                self.custom_interval = frame_ranges::seconds(para.interval);
+            }
+            Err(err) => error!("{}", err),
+        }
+    }
+
+    /// looks up whether the camera accepts ranges of frame sizes or intervals
+    fn refresh_ranges(&mut self) {
+        self.size_range = match self.camera.enum_framesizes(self.fourcc) {
+            Ok(sizes) => sizes.iter().find_map(|size| match &size.size {
+                FrameSizeEnum::Stepwise(stepwise) => Some(SizeRange::new(stepwise)),
+                FrameSizeEnum::Discrete(_) => None,
+            }),
+            Err(err) => {
+                error!("could not enumerate frame sizes: {}", err);
+                None
+            }
+        };
+        self.interval_range =
+            match self
+                .camera
+                .enum_frameintervals(self.fourcc, self.width, self.height)
+            {
+                Ok(intervals) => intervals
+                    .iter()
+                    .find_map(|interval| match &interval.interval {
+                        FrameIntervalEnum::Stepwise(stepwise) => Some(IntervalRange::new(stepwise)),
+                        FrameIntervalEnum::Discrete(_) => None,
+                    }),
+                Err(err) => {
+                    error!("could not enumerate frame intervals: {}", err);
+                    None
+                }
+            };
+        self.custom_size = (self.width, self.height);
+        self.custom_interval = frame_ranges::seconds(self.interval);
+    }
+
+    fn profile(&self) -> CameraProfile {
+        CameraProfile::new(
+            &self.full_format(),
+            self.interval,
+            self.demosaic,
+            &self.controls,
+            self.roi,
+        )
+    }
+
+    fn apply_profile(&mut self, profile: &CameraProfile) {
+        match profile.apply(&self.camera) {
+            Ok(format) => {
+                self.fourcc = format.fourcc;
+                self.width = format.width;
+                self.height = format.height;
+                self.color_space = format.colorspace;
+                self.demosaic = profile.demosaic;
+                info!("restored the settings of {}", self.profile_key);
+            }
+            Err(err) => error!(
+                "could not restore the settings of {}: {}",
+                self.profile_key, err
+            ),
+        }
+        match self.camera.params() {
+            Ok(params) => self.interval = params.interval,
+            Err(err) => error!("{}", err),
+        }
+        match fetch_controls(&self.camera) {
+            Ok(controls) => self.controls = controls,
+            Err(err) => error!("could not fetch controls {}", err),
+        }
+        self.refresh_ranges();
+        self.roi = profile.roi;
+        self.hardware_crop = roi::hardware_crop(&self.camera, self.roi, &self.full_format());
+    }
+
+    /// exposure as set by the controls, None while the camera controls it itself
+    fn exposure(&self) -> Option<Exposure> {
+        use auto_exposure::*;
+        let manual = control_value(&self.controls, CID_EXPOSURE_AUTO)
+            .map(|mode| mode == EXPOSURE_MANUAL)
+            .unwrap_or(true);
+        let autogain = control_value(&self.controls, CID_AUTOGAIN).unwrap_or(0) != 0;
+        if !manual || autogain {
+            return None;
+        }
+        Some(Exposure {
+            time: control_value(&self.controls, CID_EXPOSURE_ABSOLUTE)?,
+            gain: control_value(&self.controls, CID_GAIN),
+        })
+    }
+
+    /// switches off the automatic exposure and gain of the camera
+    fn set_manual_exposure(&mut self) -> Result<()> {
+        use auto_exposure::*;
+        if control_value(&self.controls, CID_EXPOSURE_AUTO).is_some() {
+            self.write_control(CID_EXPOSURE_AUTO, EXPOSURE_MANUAL)?;
+        }
+        if control_value(&self.controls, CID_AUTOGAIN).is_some() {
+            self.write_control(CID_AUTOGAIN, 0)?;
+        }
+        // the driver may have changed the values while it was in control
+        for id in [CID_EXPOSURE_ABSOLUTE, CID_GAIN] {
+            if let Some((_, control)) = self.controls.iter_mut().find(|(d, _)| d.id == id) {
+                *control = self.camera.control(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_exposure(&mut self, exposure: Exposure) -> Result<()> {
+        use auto_exposure::*;
+        self.write_control(CID_EXPOSURE_ABSOLUTE, exposure.time)?;
+        if let Some(gain) = exposure.gain {
+            self.write_control(CID_GAIN, gain)?;
+        }
+        Ok(())
+    }
+
+    fn start_bracketing(&mut self, stream: &mut CameraStream) {
+        if stream.corrections().subtracts_dark() {
+            error!("cannot bracket while a dark frame is subtracted");
+            return;
+        }
+        self.auto_exposure.stop();
+        if let Err(err) = self.set_manual_exposure() {
+            error!("could not switch to manual exposure: {}", err);
+            return;
+        }
+        let limits =
+            auto_exposure::control_limits(&self.controls, auto_exposure::CID_EXPOSURE_ABSOLUTE);
+        match (self.exposure(), limits) {
+            (Some(base), Some(limits)) => {
+                let exposures = self.bracketing.start(base, limits);
+                info!("started bracketing with {} exposures", exposures.len());
+                self.exposure_checked = None;
+                stream.set_bracket(Some(exposures));
+            }
+            _ => error!("the exposure of the camera is unknown, cannot bracket"),
+        }
+    }
+
+    fn stop_bracketing(&mut self, base: Exposure, stream: &mut CameraStream) {
+        stream.set_bracket(None);
+        match self.apply_exposure(base) {
+            Ok(_) => stream.set_exposure(Some(base)),
+            Err(err) => error!("could not restore exposure: {}", err),
+        }
+    }
+
+    /// sets an integer, menu or boolean control while streaming and updates the cached value
+    fn write_control(&mut self, id: u32, val: i64) -> Result<()> {
+        let cached = self.controls.iter_mut().find(|(d, _)| d.id == id);
+        let is_boolean = matches!(
+            cached.as_ref().map(|(_, control)| &control.value),
+            Some(control::Value::Boolean(_))
+        );
+        let value = || match is_boolean {
+            true => control::Value::Boolean(val != 0),
+            false => control::Value::Integer(val),
+        };
+        self.camera.set_control(Control { id, value: value() })?;
+        if let Some((_, control)) = cached {
+            control.value = value();
+        }
+        Ok(())
+    }
 }
 
 impl CamInner {
-    fn update_side_panel(&mut self, ui: &mut Ui) {
+    fn update_side_panel(&mut self, ui: &mut Ui, stream: &mut CameraStream) {
         ui.label(format!(
             "{}x{}\n{} - {}",
             self.width,
@@ -241,105 +1078,154 @@ impl CamInner {
                 Ok(formats) => {
                     for f in formats {
                         if ui
-                            .selectable_label(
-                                self.fourcc == f.fourcc,
-                                f.fourcc.str().expect("FourCC not utf-8"),
+                            .add_enabled(
+                                decode::is_supported(f.fourcc),
+                                egui::SelectableLabel::new(
+                                    self.fourcc == f.fourcc,
+                                    f.fourcc.str().expect("FourCC not utf-8"),
+                                ),
                             )
                             .clicked()
                         {
-                            CameraStream::close();
-                            match self.camera.set_format(&Format::new(
-                                self.width,
-                                self.height,
-                                f.fourcc,
-                            )) {
-                                Ok(format) => {
-                                    self.width = format.width;
-                                    self.height = format.height;
-                                    self.fourcc = format.fourcc;
-                                }
-                                Err(err) => error!("{}", err),
-                            };
+                            self.set_format(Format::new(self.width, self.height, f.fourcc), stream);
                         }
                     }
                 }
                 Err(err) => error!("{}", err),
             });
 
+        if let Some((bit_depth, cfa)) = decode::bayer_format(self.fourcc) {
+            ui.label(format!("raw {} bit {} sensor data", bit_depth, cfa));
+            if ui
+                .checkbox(&mut self.demosaic, "demosaic")
+                .on_hover_text("without demosaicing every photosite is sampled on its own")
+                .changed()
+            {
+                stream.close();
+            }
+        }
+
         egui::ComboBox::from_label("size")
             .selected_text(format!("{}x{}", self.width, self.height))
             .show_ui(ui, |ui| match self.camera.enum_framesizes(self.fourcc) {
                 Ok(sizes) => {
                     for s in sizes {
-                        for size in s.size.to_discrete() {
-                            let width = size.width;
-                            let height = size.height;
+                        // ranges are set below, listing every size could be millions of entries
+                        if let FrameSizeEnum::Discrete(size) = s.size {
                             if ui
                                 .selectable_label(
-                                    self.width == width && self.height == height,
-                                    format!("{}x{}", width, height),
+                                    self.width == size.width && self.height == size.height,
+                                    format!("{}x{}", size.width, size.height),
                                 )
                                 .clicked()
                             {
-                                CameraStream::close();
-                                match self.camera.set_format(&Format::new(
-                                    width,
-                                    height,
-                                    self.fourcc,
-                                )) {
-                                    Ok(format) => {
-                                        self.width = format.width;
-                                        self.height = format.height;
-                                        self.fourcc = format.fourcc;
-                                    }
-                                    Err(err) => error!("{}", err),
-                                }
-                            };
+                                let format = Format::new(size.width, size.height, self.fourcc);
+                                self.set_format(format, stream);
+                            }
                         }
                     }
                 }
                 Err(err) => error!("{}", err),
             });
+        if let Some(range) = self.size_range {
+            ui.label(format!("any size from {}", range));
+            ui.horizontal(|ui| {
+                let (width, height) = &mut self.custom_size;
+                ui.add(
+                    DragValue::new(width)
+                        .clamp_range(range.min_width..=range.max_width)
+                        .speed(range.step_width),
+                );
+                ui.label("x");
+                ui.add(
+                    DragValue::new(height)
+                        .clamp_range(range.min_height..=range.max_height)
+                        .speed(range.step_height),
+                );
+                if ui.button("set size").clicked() {
+                    let (width, height) = range.clamp(*width, *height);
+                    self.set_format(Format::new(width, height, self.fourcc), stream);
+                }
+            });
+        }
 
-        egui::ComboBox::from_label("FPS")
-        .selected_text((self.interval.denominator as f32 / self.interval.numerator as f32).to_string())
-        .show_ui(ui, |ui| {
-            match self
-                .camera
-                .enum_frameintervals(self.fourcc, self.width, self.height)
-            {
-                Ok(stuff) => {
-                    for elem in stuff {
-                        match elem.interval {
-                            FrameIntervalEnum::Discrete(interval) => {
+        egui::ComboBox::from_label("frame rate")
+            .selected_text(frame_ranges::describe(self.interval))
+            .show_ui(ui, |ui| {
+                match self
+                    .camera
+                    .enum_frameintervals(self.fourcc, self.width, self.height)
+                {
+                    Ok(intervals) => {
+                        for elem in intervals {
+                            // ranges are set below
+                            if let FrameIntervalEnum::Discrete(interval) = elem.interval {
                                 if ui
                                     .selectable_label(
                                         self.interval.numerator == interval.numerator
                                             && self.interval.denominator == interval.denominator,
-                                        (interval.denominator as f32 / interval.numerator as f32)
-                                            .to_string(),
+                                        frame_ranges::describe(interval),
                                     )
                                     .clicked()
                                 {
-                                    CameraStream::close();
-                                    match self.camera.set_params(&Parameters::new(interval)) {
-                                        Ok(para) => {
-                                            self.interval = para.interval;
-                                        }
-                                        Err(err) => error!("{}", err),
-                                    }
+                                    self.set_interval(interval, stream);
                                 }
                             }
-                            FrameIntervalEnum::Stepwise(_) =>{
-                                error!("if this error shows up you'll have some pain implementing this :)");
-                                todo!()
-                            },
                         }
                     }
+                    Err(err) => error!("{}", err),
+                }
+            });
+        if let Some(range) = self.interval_range {
+            ui.label(format!("any frame interval from {}", range));
+            ui.horizontal(|ui| {
+                ui.add(
+                    DragValue::new(&mut self.custom_interval)
+                        .clamp_range(range.min..=range.max)
+                        .speed(range.step.max(0.001))
+                        .suffix(" s"),
+                );
+                if ui.button("set interval").clicked() {
+                    let interval = frame_ranges::to_fraction(range.snap(self.custom_interval));
+                    self.set_interval(interval, stream);
+                }
+            });
+        }
+
+        if auto_exposure::control_value(&self.controls, auto_exposure::CID_EXPOSURE_ABSOLUTE)
+            .is_some()
+        {
+            ui.separator();
+            if self.auto_exposure.ui(ui, self.exposure()) {
+                if let Some(base) = self.bracketing.stop() {
+                    self.stop_bracketing(base, stream);
+                }
+                match self.set_manual_exposure() {
+                    Ok(_) => {
+                        self.exposure_checked = None;
+                        stream.set_exposure(self.exposure())
+                    }
+                    Err(err) => error!("could not switch to manual exposure: {}", err),
                 }
-                Err(err) => error!("{}", err),
             }
-        });
+            ui.separator();
+            let dark_subtracted = stream.corrections().subtracts_dark();
+            match self.bracketing.ui(ui, dark_subtracted) {
+                Some(BracketAction::Start) => self.start_bracketing(stream),
+                Some(BracketAction::Stop(base)) => self.stop_bracketing(base, stream),
+                None => (),
+            }
+            if let Some(exposures) = stream.bracket() {
+                ui.label(format!(
+                    "cycling through {}",
+                    exposures
+                        .iter()
+                        .map(|exposure| exposure.to_string())
+                        .join(" | ")
+                ));
+            }
+            ui.separator();
+        }
 
         ui.checkbox(&mut self.show_controls, "show controls");
         if self.show_controls {
@@ -349,133 +1235,18 @@ impl CamInner {
                     Err(err) => error!("could not fetch controls {}", err),
                 }
             }
+            let mut changed = false;
             for (description, control) in self.controls.iter_mut() {
-                update_ctrl(ui, description, control, &self.camera);
+                changed |= controls::control_ui(ui, description, control, &self.camera);
             }
-        }
-    }
-}
-
-fn update_ctrl(
-    ui: &mut Ui,
-    description: &mut control::Description,
-    control: &mut Control,
-    cam: &Device,
-) {
-    let control::Description {
-        id,
-        typ,
-        name,
-        minimum,
-        maximum,
-        step,
-        default,
-        flags,
-        items: _,
-    } = description;
-    ui.strong(name.clone());
-    if !flags.is_empty() {
-        ui.label(format!("{}", flags));
-    }
-    match typ {
-        control::Type::Integer => {
-            if let control::Value::Integer(mut val) = control.value {
-                ui.horizontal(|ui| {
-                    if ui
-                        .add(
-                            Slider::new(&mut val, *minimum..=*maximum)
-                                .clamp_to_range(true)
-                                .integer()
-                                .step_by(*step as f64),
-                        )
-                        .drag_released()
-                    {
-                        let new = Control {
-                            id: *id,
-                            value: control::Value::Integer(val),
-                        };
-                        match set_control(cam, new) {
-                            Ok(_) => control.value = control::Value::Integer(val),
-                            Err(err) => error!("could not set control {}", err),
-                        }
-                    }
-                    if ui.button("↻").clicked() {
-                        let new = Control {
-                            id: *id,
-                            value: control::Value::Integer(*default),
-                        };
-                        match set_control(cam, new) {
-                            Ok(_) => control.value = control::Value::Integer(*default),
-                            Err(err) => error!("could not set default {}", err),
-                        }
-                    }
-                });
-            } else {
-                error!(
-                    "control description with interger type was: {:?}",
-                    control.value
-                );
-                panic!()
-            };
-        }
-        control::Type::Boolean => {
-            if let control::Value::Boolean(mut b) = control.value {
-                if ui.checkbox(&mut b, "").clicked() {
-                    let new = Control {
-                        id: *id,
-                        value: control::Value::Boolean(b),
-                    };
-                    match set_control(cam, new) {
-                        Ok(_) => control.value = control::Value::Boolean(b),
-                        Err(err) => error!("could not set control {}", err),
-                    }
+            if changed {
+                // other controls may have become active or changed their value
+                match fetch_controls(&self.camera) {
+                    Ok(vec) => self.controls = vec,
+                    Err(err) => error!("could not fetch controls {}", err),
                 }
-            } else {
-                error!(
-                    "control description with boolean type was {:?}",
-                    control.value
-                );
-                panic!()
+                stream.set_exposure(self.exposure());
             }
         }
-        control::Type::Menu => {
-            if let control::Value::Integer(mut val) = control.value {
-                ui.horizontal(|ui| {
-                    if ui
-                        .add(
-                            Slider::new(&mut val, *minimum..=*maximum)
-                                .clamp_to_range(true)
-                                .integer()
-                                .step_by(*step as f64),
-                        )
-                        .drag_released()
-                    {
-                        let new = Control {
-                            id: *id,
-                            value: control::Value::Integer(val),
-                        };
-                        match set_control(cam, new) {
-                            Ok(_) => control.value = control::Value::Integer(val),
-                            Err(err) => error!("could not set control {}", err),
-                        }
-                    }
-                    if ui.button("↻").clicked() {
-                        let new = Control {
-                            id: *id,
-                            value: control::Value::Integer(*default),
-                        };
-                        match set_control(cam, new) {
-                            Ok(_) => control.value = control::Value::Integer(*default),
-                            Err(err) => error!("could not set default {}", err),
-                        }
-                    }
-                });
-            } else {
-                ui.label(format!("{:?}", control.value));
-            }
-        }
-        _ => {
-            ui.label(format!("not implemented, because it has type: {}", typ));
-        }
     }
 }
@@ -0,0 +1,302 @@
+use std::fmt;
+
+use egui::{Slider, Ui};
+use log::info;
+use v4l::{control, Control};
+
+/// V4L2_CID_EXPOSURE_AUTO, a menu where 1 is manual exposure
+pub const CID_EXPOSURE_AUTO: u32 = 0x009a_0901;
+/// V4L2_CID_EXPOSURE_ABSOLUTE in units of 100 µs
+pub const CID_EXPOSURE_ABSOLUTE: u32 = 0x009a_0902;
+pub const CID_AUTOGAIN: u32 = 0x0098_0912;
+pub const CID_GAIN: u32 = 0x0098_0913;
+pub const EXPOSURE_MANUAL: i64 = 1;
+
+/// samples at or above this fraction of full scale are treated as saturated
+pub const SATURATED: f32 = 0.99;
+/// the gain is changed in this many steps over its range
+const GAIN_STEPS: i64 = 16;
+
+/// Exposure settings of the camera while a frame was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Exposure {
+    /// exposure time in units of 100 µs as used by V4L2
+    pub time: i64,
+    pub gain: Option<i64>,
+}
+
+impl Exposure {
+    pub fn seconds(&self) -> f32 {
+        self.time as f32 * 1e-4
+    }
+}
+
+impl fmt::Display for Exposure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1} ms", self.seconds() * 1e3)?;
+        if let Some(gain) = self.gain {
+            write!(f, ", gain {}", gain)?;
+        }
+        Ok(())
+    }
+}
+
+/// Range of a camera control as reported by the driver.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub minimum: i64,
+    pub maximum: i64,
+    pub step: i64,
+}
+
+impl Limits {
+    pub fn from_description(description: &control::Description) -> Self {
+        Self {
+            minimum: description.minimum,
+            maximum: description.maximum,
+            step: (description.step as i64).max(1),
+        }
+    }
+
+    pub fn clamp(&self, val: i64) -> i64 {
+        let val = val.clamp(self.minimum, self.maximum);
+        self.minimum + (val - self.minimum) / self.step * self.step
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Off,
+    Adjusting,
+    Locked,
+    AtLimit,
+}
+
+/// Software auto exposure that brings the brightest sample of the spectrum to a fixed
+/// fraction of full scale and keeps the exposure constant afterwards.
+pub struct AutoExposure {
+    state: State,
+    /// wanted peak as fraction of full scale
+    target: f32,
+    /// accepted relative deviation from the target
+    tolerance: f32,
+    last_peak: Option<f32>,
+}
+
+impl Default for AutoExposure {
+    fn default() -> Self {
+        Self {
+            state: State::Off,
+            target: 0.8,
+            tolerance: 0.05,
+            last_peak: None,
+        }
+    }
+}
+
+impl AutoExposure {
+    pub fn is_adjusting(&self) -> bool {
+        self.state == State::Adjusting
+    }
+
+    pub fn stop(&mut self) {
+        self.state = State::Off
+    }
+
+    /// returns true if the controller was just switched on
+    pub fn ui(&mut self, ui: &mut Ui, exposure: Option<Exposure>) -> bool {
+        let mut started = false;
+        ui.strong("Auto exposure");
+        ui.add(Slider::new(&mut self.target, 0.1..=0.95).text("target peak"));
+        ui.add(Slider::new(&mut self.tolerance, 0.01..=0.2).text("tolerance"));
+        ui.horizontal(|ui| {
+            let label = match self.state {
+                State::Off => "adjust exposure",
+                _ => "readjust",
+            };
+            if ui.button(label).clicked() {
+                self.state = State::Adjusting;
+                self.last_peak = None;
+                started = true;
+            }
+            if self.state != State::Off && ui.button("off").clicked() {
+                self.state = State::Off
+            }
+        });
+        let state = match self.state {
+            State::Off => "off",
+            State::Adjusting => "adjusting",
+            State::Locked => "locked",
+            State::AtLimit => "locked at the limit of the camera",
+        };
+        ui.label(format!("state: {}", state));
+        if let Some(peak) = self.last_peak {
+            ui.label(format!("peak: {:.0} %", peak * 100.0));
+        }
+        match exposure {
+            Some(exposure) => ui.label(format!("exposure: {}", exposure)),
+            None => ui.label("exposure: unknown, automatic exposure of the camera"),
+        };
+        started
+    }
+
+    /// Takes the peak of a frame taken with `current` and returns the next exposure to try.
+    /// `peak` is the brightest sample as fraction of full scale.
+    pub fn update(
+        &mut self,
+        peak: f32,
+        current: Exposure,
+        time_limits: Limits,
+        gain_limits: Option<Limits>,
+    ) -> Option<Exposure> {
+        if self.state != State::Adjusting {
+            return None;
+        }
+        self.last_peak = Some(peak);
+        if peak < SATURATED && (peak / self.target - 1.0).abs() <= self.tolerance {
+            info!("auto exposure locked at {}", current);
+            self.state = State::Locked;
+            return None;
+        }
+        // a saturated peak does not tell how much too bright the frame is
+        let ratio = if peak >= SATURATED {
+            0.5
+        } else {
+            self.target / peak.max(1e-3)
+        };
+
+        let gain_step = gain_limits
+            .map(|limits| ((limits.maximum - limits.minimum) / GAIN_STEPS).max(limits.step))
+            .unwrap_or(0);
+        let mut next = current;
+        match (ratio > 1.0, current.gain, gain_limits) {
+            // raise the gain only once the exposure time is exhausted
+            (true, Some(gain), Some(limits)) if current.time >= time_limits.maximum => {
+                next.gain = Some(limits.clamp(gain + gain_step))
+            }
+            // lower the gain first to keep the noise down
+            (false, Some(gain), Some(limits)) if gain > limits.minimum => {
+                next.gain = Some(limits.clamp(gain - gain_step))
+            }
+            _ => {
+                let time = (current.time as f32 * ratio).round() as i64;
+                next.time = time_limits.clamp(time);
+            }
+        }
+        if next == current {
+            info!("auto exposure reached the limit at {}", current);
+            self.state = State::AtLimit;
+            return None;
+        }
+        Some(next)
+    }
+}
+
+/// current value of an integer or menu control
+pub fn control_value(controls: &[(control::Description, Control)], id: u32) -> Option<i64> {
+    controls
+        .iter()
+        .find(|(description, _)| description.id == id)
+        .and_then(|(_, control)| match control.value {
+            control::Value::Integer(val) => Some(val),
+            control::Value::Boolean(val) => Some(val as i64),
+            _ => None,
+        })
+}
+
+pub fn control_limits(controls: &[(control::Description, Control)], id: u32) -> Option<Limits> {
+    controls
+        .iter()
+        .find(|(description, _)| description.id == id)
+        .map(|(description, _)| Limits::from_description(description))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME: Limits = Limits {
+        minimum: 1,
+        maximum: 1000,
+        step: 1,
+    };
+    const GAIN: Limits = Limits {
+        minimum: 0,
+        maximum: 100,
+        step: 5,
+    };
+
+    fn adjusting() -> AutoExposure {
+        AutoExposure {
+            state: State::Adjusting,
+            ..AutoExposure::default()
+        }
+    }
+
+    #[test]
+    fn limits_snap_to_the_steps_of_the_control() {
+        assert_eq!(GAIN.clamp(-10), 0);
+        assert_eq!(GAIN.clamp(12), 10);
+        assert_eq!(GAIN.clamp(500), 100);
+        let odd = Limits {
+            minimum: 3,
+            maximum: 20,
+            step: 4,
+        };
+        // the maximum is not on a step, so the last step below it is used
+        assert_eq!(odd.clamp(20), 19);
+    }
+
+    #[test]
+    fn exposure_time_scales_with_the_missing_brightness() {
+        let current = Exposure {
+            time: 100,
+            gain: None,
+        };
+        let next = adjusting().update(0.2, current, TIME, None).unwrap();
+        assert_eq!(next.time, 400);
+        // a saturated peak only halves the exposure
+        let next = adjusting().update(1.0, current, TIME, None).unwrap();
+        assert_eq!(next.time, 50);
+    }
+
+    #[test]
+    fn exposure_locks_within_the_tolerance() {
+        let mut auto_exposure = adjusting();
+        let current = Exposure {
+            time: 100,
+            gain: None,
+        };
+        assert!(auto_exposure.update(0.81, current, TIME, None).is_none());
+        assert_eq!(auto_exposure.state, State::Locked);
+    }
+
+    #[test]
+    fn gain_is_only_raised_at_the_longest_exposure_time() {
+        let short = Exposure {
+            time: 100,
+            gain: Some(0),
+        };
+        let next = adjusting().update(0.1, short, TIME, Some(GAIN)).unwrap();
+        assert_eq!(next.gain, Some(0));
+        assert!(next.time > short.time);
+
+        let longest = Exposure {
+            time: TIME.maximum,
+            gain: Some(0),
+        };
+        let next = adjusting().update(0.1, longest, TIME, Some(GAIN)).unwrap();
+        assert_eq!(next.time, TIME.maximum);
+        assert!(next.gain.unwrap() > 0);
+
+        let mut auto_exposure = adjusting();
+        let exhausted = Exposure {
+            time: TIME.maximum,
+            gain: Some(GAIN.maximum),
+        };
+        assert!(auto_exposure
+            .update(0.1, exhausted, TIME, Some(GAIN))
+            .is_none());
+        assert_eq!(auto_exposure.state, State::AtLimit);
+    }
+}
@@ -0,0 +1,287 @@
+use std::cmp::Ordering;
+
+use super::{Crop, Image};
+
+/// scale factor between the median absolute deviation and the standard deviation of a normal distribution
+const MAD_TO_SIGMA: f32 = 1.4826;
+
+/// Pixels that should never be used, stored per camera in coordinates of the full frame.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct BadPixelMask {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<(u32, u32)>,
+}
+
+impl BadPixelMask {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pixels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pixels.is_empty()
+    }
+
+    pub fn add(&mut self, x: usize, y: usize) {
+        let pixel = (x as u32, y as u32);
+        if let Err(index) = self.pixels.binary_search(&pixel) {
+            self.pixels.insert(index, pixel)
+        }
+    }
+
+    /// adds the pixels of `other`, a mask of a different size is replaced
+    pub fn merge(&mut self, other: BadPixelMask) {
+        if self.width != other.width || self.height != other.height {
+            *self = other;
+            return;
+        }
+        for (x, y) in other.pixels {
+            self.add(x as usize, y as usize)
+        }
+    }
+
+    pub fn fits(&self, img: &Image) -> bool {
+        (self.width, self.height) == img.full_size()
+    }
+
+    /// one entry per pixel of `img`, row by row
+    pub fn to_dense(&self, img: &Image) -> Vec<bool> {
+        let (x0, y0) = img.origin();
+        let mut dense = vec![false; img.width * img.height];
+        for (x, y) in self.pixels.iter() {
+            let (x, y) = (*x as usize, *y as usize);
+            if (x0..x0 + img.width).contains(&x) && (y0..y0 + img.height).contains(&y) {
+                dense[(y - y0) * img.width + x - x0] = true;
+            }
+        }
+        dense
+    }
+}
+
+/// Finds pixels whose dark level or dark noise lies far outside of the rest of the sensor.
+/// `mean` and `std` hold one value per pixel of an image that was cut out of the full frame
+/// as given by `crop`, `threshold` is in robust standard deviations.
+pub fn detect_hot_pixels(
+    mean: &[f32],
+    std: &[f32],
+    width: usize,
+    height: usize,
+    crop: Option<Crop>,
+    threshold: f32,
+) -> BadPixelMask {
+    let (mean_median, mean_mad) = median_and_mad(mean);
+    let (std_median, _) = median_and_mad(std);
+    // a perfectly quiet sensor would otherwise flag every pixel that is one count off
+    let spread = (mean_mad * MAD_TO_SIGMA).max(std_median).max(1.0);
+
+    let mut mask = match crop {
+        Some(crop) => BadPixelMask::new(crop.full_width, crop.full_height),
+        None => BadPixelMask::new(width, height),
+    };
+    let (x0, y0) = crop.map(|crop| (crop.x, crop.y)).unwrap_or((0, 0));
+    for (i, (mean, std)) in mean.iter().zip(std.iter()).enumerate() {
+        let hot = mean - mean_median > threshold * spread;
+        let noisy = std_median > 0.0 && *std > threshold * std_median;
+        if hot || noisy {
+            mask.add(x0 + i % width, y0 + i / width)
+        }
+    }
+    mask
+}
+
+fn median_and_mad(vals: &[f32]) -> (f32, f32) {
+    if vals.is_empty() {
+        return (0.0, 0.0);
+    }
+    let mut sorted = vals.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    let median = sorted[sorted.len() / 2];
+    let mut deviations = sorted
+        .iter()
+        .map(|val| (val - median).abs())
+        .collect::<Vec<_>>();
+    deviations.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    (median, deviations[deviations.len() / 2])
+}
+
+/// Flags single pixels that suddenly got much brighter than in the last frame and than their
+/// neighbours, as caused by cosmic rays or other transient events.
+#[derive(Default)]
+pub struct TransientDetector {
+    previous: Option<(usize, usize, Vec<f32>)>,
+}
+
+impl TransientDetector {
+    /// `threshold` is relative to full scale, returns one entry per pixel
+    pub fn detect(&mut self, img: &Image, threshold: f32) -> Vec<bool> {
+        let width = img.width;
+        let height = img.height;
+        let current = pixel_values(img);
+        // raw images only have a single non zero channel per pixel, the nearest photosites of
+        // the same colour are two pixels away
+        let (channels, distance) = if img.cfa.is_some() {
+            (1.0, 2)
+        } else {
+            (3.0, 1)
+        };
+        let threshold = threshold * channels * u16::MAX as f32;
+        let mut transient = vec![false; width * height];
+        if let Some((prev_width, prev_height, previous)) = self.previous.as_ref() {
+            if *prev_width == width && *prev_height == height {
+                for y in 0..height {
+                    for x in 0..width {
+                        let i = y * width + x;
+                        if current[i] - previous[i] <= threshold {
+                            continue;
+                        }
+                        let neighbours = [
+                            (x.wrapping_sub(distance), y),
+                            (x + distance, y),
+                            (x, y.wrapping_sub(distance)),
+                            (x, y + distance),
+                        ];
+                        let brightest_neighbour = neighbours
+                            .iter()
+                            .filter(|(x, y)| *x < width && *y < height)
+                            .map(|(x, y)| current[y * width + x])
+                            .fold(0.0, f32::max);
+                        transient[i] = current[i] - brightest_neighbour > threshold;
+                    }
+                }
+            }
+        }
+        self.previous = Some((width, height, current));
+        transient
+    }
+}
+
+/// sum of all channels of every pixel
+pub fn pixel_values(img: &Image) -> Vec<f32> {
+    img.data
+        .chunks_exact(3)
+        .map(|rgb| rgb.iter().map(|val| *val as f32).sum())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_module::{CfaPattern, Roi};
+
+    const WIDTH: usize = 8;
+    const HEIGHT: usize = 6;
+
+    /// raw frame under light that only the red photosites see, plus `spike` at (3, 2)
+    fn red_light(level: u16, spike: u16) -> Image {
+        let cfa = CfaPattern::Rggb;
+        let mosaic = (0..WIDTH * HEIGHT)
+            .map(|i| {
+                let (x, y) = (i % WIDTH, i / WIDTH);
+                let val = if cfa.channel(x, y) == 0 { level } else { 0 };
+                if (x, y) == (3, 2) {
+                    val + spike
+                } else {
+                    val
+                }
+            })
+            .collect();
+        Image::new_raw(WIDTH, HEIGHT, mosaic, 16, cfa)
+    }
+
+    /// rgb frame with every pixel at `val(x, y)` in all channels
+    fn rgb(val: impl Fn(usize, usize) -> u16) -> Image {
+        let data = (0..WIDTH * HEIGHT)
+            .flat_map(|i| [val(i % WIDTH, i / WIDTH); 3])
+            .collect();
+        Image::new(WIDTH, HEIGHT, data, 16)
+    }
+
+    #[test]
+    fn hot_and_noisy_pixels_are_detected_in_full_frame_coordinates() {
+        let mut mean: Vec<f32> = (0..WIDTH * HEIGHT)
+            .map(|i| 100.0 + (i % 3) as f32)
+            .collect();
+        let mut std = vec![4.0; WIDTH * HEIGHT];
+        mean[WIDTH + 2] = 500.0;
+        std[4 * WIDTH + 5] = 60.0;
+        let crop = Crop {
+            x: 10,
+            y: 20,
+            full_width: 64,
+            full_height: 48,
+        };
+        let mask = detect_hot_pixels(&mean, &std, WIDTH, HEIGHT, Some(crop), 8.0);
+        assert_eq!((mask.width, mask.height), (64, 48));
+        assert_eq!(mask.pixels, [(12, 21), (15, 24)]);
+    }
+
+    #[test]
+    fn masks_are_merged_and_cut_to_the_image() {
+        let mut mask = BadPixelMask::new(WIDTH, HEIGHT);
+        mask.add(1, 1);
+        mask.add(5, 4);
+        let mut other = BadPixelMask::new(WIDTH, HEIGHT);
+        other.add(5, 4);
+        other.add(7, 0);
+        mask.merge(other);
+        assert_eq!(mask.pixels, [(1, 1), (5, 4), (7, 0)]);
+
+        let img = rgb(|_, _| 0).cropped(Roi {
+            x: 4,
+            y: 0,
+            width: 4,
+            height: 5,
+        });
+        assert!(mask.fits(&img));
+        let dense = mask.to_dense(&img);
+        let flagged: Vec<usize> = (0..dense.len()).filter(|i| dense[*i]).collect();
+        assert_eq!(flagged, [3, 4 * 4 + 1]);
+
+        // a mask of another sensor size replaces the old one
+        let mut other = BadPixelMask::new(WIDTH + 2, HEIGHT);
+        other.add(9, 5);
+        mask.merge(other);
+        assert_eq!((mask.width, mask.len()), (WIDTH + 2, 1));
+        assert!(!mask.fits(&img));
+    }
+
+    #[test]
+    fn single_pixel_spikes_are_transient() {
+        let mut detector = TransientDetector::default();
+        let dark = rgb(|_, _| 1000);
+        assert!(detector.detect(&dark, 0.1).iter().all(|t| !t));
+        let spike = rgb(|x, y| if (x, y) == (2, 3) { 40_000 } else { 1000 });
+        let transient = detector.detect(&spike, 0.1);
+        assert_eq!(transient.iter().filter(|t| **t).count(), 1);
+        assert!(transient[3 * WIDTH + 2]);
+    }
+
+    #[test]
+    fn smooth_gradients_are_not_transient() {
+        let mut detector = TransientDetector::default();
+        detector.detect(&rgb(|_, _| 0), 0.1);
+        // every pixel gets much brighter, but so do its neighbours
+        let gradient = rgb(|x, y| (20_000 + 4_000 * x + 1_000 * y) as u16);
+        assert!(detector.detect(&gradient, 0.1).iter().all(|t| !t));
+    }
+
+    #[test]
+    fn raw_pixels_are_compared_with_their_own_colour() {
+        let mut detector = TransientDetector::default();
+        detector.detect(&red_light(0, 0), 0.1);
+        // the red photosites are far brighter than their green neighbours
+        let transient = detector.detect(&red_light(30_000, 0), 0.1);
+        assert!(transient.iter().all(|transient| !transient));
+        let transient = detector.detect(&red_light(30_000, 20_000), 0.1);
+        assert_eq!(transient.iter().filter(|transient| **transient).count(), 1);
+        assert!(transient[2 * WIDTH + 3]);
+    }
+}
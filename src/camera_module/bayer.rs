@@ -0,0 +1,116 @@
+use std::fmt;
+
+use super::Image;
+
+/// Colour filter array layout, named after the top left 2x2 block of the sensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum CfaPattern {
+    Rggb,
+    Bggr,
+    Grbg,
+    Gbrg,
+}
+
+impl CfaPattern {
+    /// channel (0 = red, 1 = green, 2 = blue) of the photosite at (x, y)
+    pub const fn channel(&self, x: usize, y: usize) -> usize {
+        let layout = match self {
+            CfaPattern::Rggb => [[0, 1], [1, 2]],
+            CfaPattern::Bggr => [[2, 1], [1, 0]],
+            CfaPattern::Grbg => [[1, 0], [2, 1]],
+            CfaPattern::Gbrg => [[1, 2], [0, 1]],
+        };
+        layout[y % 2][x % 2]
+    }
+
+    /// pattern of an image cropped at (x, y)
+    pub const fn shifted(self, x: usize, y: usize) -> Self {
+        let horizontal = match (self, x % 2) {
+            (pattern, 0) => pattern,
+            (CfaPattern::Rggb, _) => CfaPattern::Grbg,
+            (CfaPattern::Grbg, _) => CfaPattern::Rggb,
+            (CfaPattern::Bggr, _) => CfaPattern::Gbrg,
+            (CfaPattern::Gbrg, _) => CfaPattern::Bggr,
+        };
+        match (horizontal, y % 2) {
+            (pattern, 0) => pattern,
+            (CfaPattern::Rggb, _) => CfaPattern::Gbrg,
+            (CfaPattern::Gbrg, _) => CfaPattern::Rggb,
+            (CfaPattern::Bggr, _) => CfaPattern::Grbg,
+            (CfaPattern::Grbg, _) => CfaPattern::Bggr,
+        }
+    }
+}
+
+impl fmt::Display for CfaPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CfaPattern::Rggb => write!(f, "RGGB"),
+            CfaPattern::Bggr => write!(f, "BGGR"),
+            CfaPattern::Grbg => write!(f, "GRBG"),
+            CfaPattern::Gbrg => write!(f, "GBRG"),
+        }
+    }
+}
+
+/// Fills in the missing channels of a raw image by averaging the neighbouring photosites
+/// of the same colour (bilinear demosaicing).
+pub fn demosaic(img: &mut Image) {
+    let cfa = match img.cfa {
+        Some(cfa) => cfa,
+        None => return,
+    };
+    let width = img.width;
+    let height = img.height;
+    let raw = &img.data;
+    let mut data = Vec::with_capacity(raw.len());
+    for y in 0..height {
+        for x in 0..width {
+            let own = cfa.channel(x, y);
+            for channel in 0..3 {
+                if channel == own {
+                    data.push(raw[3 * (y * width + x) + channel]);
+                    continue;
+                }
+                let mut total = 0u32;
+                let mut count = 0u32;
+                for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                        if cfa.channel(nx, ny) == channel {
+                            total += raw[3 * (ny * width + nx) + channel] as u32;
+                            count += 1;
+                        }
+                    }
+                }
+                data.push(total.checked_div(count).unwrap_or(0) as u16);
+            }
+        }
+    }
+    img.data = data;
+    img.cfa = None;
+    img.texture = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATTERNS: [CfaPattern; 4] = [
+        CfaPattern::Rggb,
+        CfaPattern::Bggr,
+        CfaPattern::Grbg,
+        CfaPattern::Gbrg,
+    ];
+
+    #[test]
+    fn shifted_pattern_matches_the_cropped_photosites() {
+        for cfa in PATTERNS {
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1), (2, 3)] {
+                let shifted = cfa.shifted(dx, dy);
+                for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    assert_eq!(shifted.channel(x, y), cfa.channel(x + dx, y + dy));
+                }
+            }
+        }
+    }
+}
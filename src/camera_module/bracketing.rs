@@ -0,0 +1,92 @@
+use egui::{Button, Slider, Ui};
+
+use super::auto_exposure::{Exposure, Limits};
+
+/// Settings of the exposure bracket used for HDR spectra.
+pub struct Bracketing {
+    steps: usize,
+    /// ratio between the longest and the shortest exposure
+    span: f32,
+    /// exposure of the camera before bracketing started, restored afterwards
+    base: Option<Exposure>,
+}
+
+/// What the user asked for in [`Bracketing::ui`].
+pub enum BracketAction {
+    Start,
+    Stop(Exposure),
+}
+
+impl Default for Bracketing {
+    fn default() -> Self {
+        Self {
+            steps: 4,
+            span: 16.0,
+            base: None,
+        }
+    }
+}
+
+impl Bracketing {
+    pub fn is_active(&self) -> bool {
+        self.base.is_some()
+    }
+
+    /// `dark_subtracted` blocks starting, as the dark frame fits only one of the exposures
+    pub fn ui(&mut self, ui: &mut Ui, dark_subtracted: bool) -> Option<BracketAction> {
+        ui.strong("Exposure bracketing");
+        let mut action = None;
+        ui.add_enabled_ui(!self.is_active(), |ui| {
+            ui.add(Slider::new(&mut self.steps, 2..=8).text("exposures"));
+            ui.add(
+                Slider::new(&mut self.span, 2.0..=256.0)
+                    .logarithmic(true)
+                    .text("span"),
+            );
+        });
+        match self.base {
+            Some(base) => {
+                if ui.button("stop bracketing").clicked() {
+                    self.base = None;
+                    action = Some(BracketAction::Stop(base));
+                }
+            }
+            None => {
+                if ui
+                    .add_enabled(!dark_subtracted, Button::new("start bracketing"))
+                    .on_hover_text("the current exposure is the shortest one of the bracket")
+                    .clicked()
+                {
+                    action = Some(BracketAction::Start)
+                }
+                if dark_subtracted {
+                    ui.label(
+                        "the dark frame fits only one exposure, \
+                        discard it or stop subtracting it to bracket",
+                    );
+                }
+            }
+        }
+        action
+    }
+
+    /// Exposures from `base` up to `span` times longer, spaced evenly in log space.
+    pub fn start(&mut self, base: Exposure, limits: Limits) -> Vec<Exposure> {
+        self.base = Some(base);
+        let mut exposures: Vec<Exposure> = (0..self.steps)
+            .map(|i| {
+                let factor = self.span.powf(i as f32 / (self.steps - 1) as f32);
+                Exposure {
+                    time: limits.clamp((base.time as f32 * factor).round() as i64),
+                    gain: base.gain,
+                }
+            })
+            .collect();
+        exposures.dedup();
+        exposures
+    }
+
+    pub fn stop(&mut self) -> Option<Exposure> {
+        self.base.take()
+    }
+}
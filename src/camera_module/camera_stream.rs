@@ -1,90 +1,305 @@
-use std::sync::Mutex;
+use std::{
+    io::{ErrorKind, Result},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
-use egui::ColorImage;
-use image::{buffer::ConvertBuffer, ImageBuffer, Rgb, RgbaImage};
-use log::error;
-use once_cell::sync::Lazy;
-use v4l::{buffer, io::traits::CaptureStream, prelude::*};
+use log::{error, info, warn};
 
-use super::Image;
+use super::{
+    frame_buffer::{self, Frame, FrameBuffer},
+    recorder::{Recorder, Session},
+    Corrections, Exposure, FrameSource, Image, Roi,
+};
 
-static CAMERA_STREAM: Lazy<Mutex<Option<MmapStream>>> = Lazy::new(Default::default);
+/// number of frames kept for consumers that are slower than the camera
+const FRAME_BUFFER_CAPACITY: usize = 16;
+/// pause after a failed frame so a broken source does not spin
+const ERROR_BACKOFF: Duration = Duration::from_millis(100);
+/// consecutive failed frames after which the source is considered gone
+const MAX_FAILURES: usize = 50;
+/// errno values with which V4L2 reports an unplugged device
+const ENODEV: i32 = 19;
+const ENXIO: i32 = 6;
+/// frames that may still be queued in the driver when the exposure changes
+const EXPOSURE_SETTLE_FRAMES: usize = 6;
+/// shortest time to wait for a fresh frame when one is explicitly requested,
+/// for long exposures the wait is extended to a few frame intervals
+const FRESH_FRAME_TIMEOUT: Duration = Duration::from_secs(2);
 
-pub fn make_img_buf(buf: &[u8], width: u32, height: u32) -> Option<ImageBuffer<Rgb<u8>, &[u8]>> {
-    let image = ImageBuffer::from_raw(width, height, buf)?;
-    Some(image as ImageBuffer<Rgb<u8>, &[u8]>)
+/// Acquires frames of the currently active source on a background thread, applies the
+/// corrections and keeps the most recent ones in a ring buffer.
+pub struct CameraStream {
+    acquisition: Option<Acquisition>,
+    corrections: Arc<Mutex<Corrections>>,
+    frames: Arc<FrameBuffer>,
+    exposure: Arc<Mutex<ExposureTag>>,
+    /// frames are cropped to this before the corrections are applied
+    roi: Arc<Mutex<Option<Roi>>>,
+    /// gaps in the sequence numbers of the driver since the source was opened
+    dropped: Arc<AtomicU64>,
+    /// writes the frames to disk before they are corrected
+    recorder: Arc<Mutex<Option<Recorder>>>,
+    /// exposures the camera cycles through while bracketing
+    bracket: Option<Vec<Exposure>>,
 }
 
-pub struct CameraStream;
+/// exposure attached to the acquired frames
+#[derive(Default)]
+struct ExposureTag {
+    exposure: Option<Exposure>,
+    /// number of frames that are still left untagged after a change
+    settle: usize,
+}
+
+struct Acquisition {
+    name: String,
+    running: Arc<AtomicBool>,
+    /// set by the thread when it gave up on the source
+    lost: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Default for CameraStream {
+    fn default() -> Self {
+        Self {
+            acquisition: None,
+            corrections: Arc::default(),
+            frames: Arc::new(FrameBuffer::new(FRAME_BUFFER_CAPACITY)),
+            exposure: Arc::default(),
+            roi: Arc::default(),
+            dropped: Arc::default(),
+            recorder: Arc::default(),
+            bracket: None,
+        }
+    }
+}
 
 impl CameraStream {
-    pub fn get_img(width: u32, height: u32) -> Option<Image> {
-        match CAMERA_STREAM.lock().unwrap().as_mut()?.next() {
-            Ok((buf, meta)) => match make_img_buf(buf, width, height) {
-                Some(img) => Some(img.into()),
-                None => {
-                    error!(
-                        "could not load image frame: {}, {} bytes received",
-                        meta.sequence, meta.bytesused
-                    );
-                    None
-                }
-            },
-            Err(err) => {
-                error!("could not get frame: {}", err);
+    /// the newest frame, without waiting for the camera
+    pub fn latest(&self) -> Option<Frame> {
+        self.frames.latest()
+    }
+
+    /// all buffered frames newer than `sequence`, oldest first
+    pub fn frames_since(&self, sequence: Option<u64>) -> Vec<Frame> {
+        self.frames.since(sequence)
+    }
+
+    /// waits for a frame that was taken after this call, e.g. for calibration images
+    pub fn get_img(&mut self) -> Option<Image> {
+        self.acquisition.as_ref()?;
+        let sequence = self.latest().map(|frame| frame.sequence);
+        let timeout = match self.frames.interval() {
+            Some(interval) => FRESH_FRAME_TIMEOUT.max(interval * 3),
+            None => FRESH_FRAME_TIMEOUT,
+        };
+        match self.frames.wait_newer(sequence, timeout) {
+            Some(frame) => Some(frame.img.as_ref().clone()),
+            None => {
+                error!("timed out waiting for a frame");
                 None
             }
         }
     }
 
-    pub fn get_img_as_texture(
-        ctx: &egui::Context,
-        width: u32,
-        height: u32,
-    ) -> Option<egui::TextureHandle> {
-        match CAMERA_STREAM.lock().unwrap().as_mut()?.next() {
-            Ok((buf, meta)) => match make_img_buf(buf, width, height) {
-                Some(image) => {
-                    let image: RgbaImage = image.convert();
-                    let image = ColorImage::from_rgba_unmultiplied(
-                        [width as usize, height as usize],
-                        &image,
-                    );
-                    Some(ctx.load_texture(
-                        format!("frame {}", meta.sequence),
-                        image,
-                        egui::TextureFilter::Linear,
-                    ))
-                }
-                None => {
-                    error!(
-                        "could not load image frame: {},   {} bytes received",
-                        meta.sequence, meta.bytesused
-                    );
-                    None
-                }
-            },
-            Err(err) => {
-                error!("failed to read frame: {}", err);
-                None
+    pub fn open(&mut self, source: Box<dyn FrameSource>) {
+        self.close();
+        let name = source.name();
+        self.corrections().set_camera(source.key(), &name);
+        *self.exposure.lock().unwrap() = ExposureTag::default();
+        self.dropped.store(0, Ordering::Relaxed);
+        let running = Arc::new(AtomicBool::new(true));
+        let lost = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let running = running.clone();
+            let lost = lost.clone();
+            let shared = Shared {
+                corrections: self.corrections.clone(),
+                frames: self.frames.clone(),
+                exposure: self.exposure.clone(),
+                roi: self.roi.clone(),
+                dropped: self.dropped.clone(),
+                recorder: self.recorder.clone(),
+            };
+            thread::spawn(move || acquire(source, running, lost, shared))
+        };
+        info!("started acquisition from {}", name);
+        self.acquisition = Some(Acquisition {
+            name,
+            running,
+            lost,
+            handle,
+        })
+    }
+
+    /// Stops the acquisition thread and waits for it to release the source. A recording ends
+    /// as well, as its settings are only valid for this source.
+    pub fn close(&mut self) {
+        self.stop_recording();
+        if let Some(acquisition) = self.acquisition.take() {
+            acquisition.running.store(false, Ordering::Relaxed);
+            if acquisition.handle.join().is_err() {
+                error!("the acquisition thread of {} panicked", acquisition.name)
             }
+            self.frames.clear()
         }
     }
 
-    pub fn open_stream(camera: &Device) {
-        if CAMERA_STREAM.lock().unwrap().is_none() {
-            match MmapStream::with_buffers(camera, buffer::Type::VideoCapture, 5) {
-                Ok(stream) => *CAMERA_STREAM.lock().unwrap() = Some(stream),
-                Err(err) => error!("Could not open stream:   {}", err),
-            }
+    pub fn is_open(&self) -> bool {
+        self.acquisition.is_some()
+    }
+
+    /// the source stopped delivering frames, e.g. because the camera was unplugged
+    pub fn is_lost(&self) -> bool {
+        self.acquisition
+            .as_ref()
+            .map(|acquisition| acquisition.lost.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    /// Tags the following frames with `exposure` once the frames taken before the change
+    /// have passed, frames in between carry no exposure.
+    pub fn set_exposure(&self, exposure: Option<Exposure>) {
+        let mut tag = self.exposure.lock().unwrap();
+        if tag.exposure != exposure {
+            tag.exposure = exposure;
+            tag.settle = EXPOSURE_SETTLE_FRAMES;
         }
     }
 
-    pub fn close() {
-        *CAMERA_STREAM.lock().unwrap() = None
+    /// crops the following frames to `roi`, given in pixels of the full frame
+    pub fn set_roi(&self, roi: Option<Roi>) {
+        *self.roi.lock().unwrap() = roi;
+    }
+
+    pub fn roi(&self) -> Option<Roi> {
+        *self.roi.lock().unwrap()
+    }
+
+    /// frames the driver dropped since the source was opened, e.g. because they were not
+    /// dequeued in time
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// records every following frame of the current source to `dir`
+    pub fn start_recording(&self, dir: PathBuf, session: &Session) -> Result<()> {
+        let recorder = Recorder::new(dir, session)?;
+        *self.recorder.lock().unwrap() = Some(recorder);
+        Ok(())
+    }
+
+    /// waits until all recorded frames are written
+    pub fn stop_recording(&self) {
+        let recorder = self.recorder.lock().unwrap().take();
+        drop(recorder)
+    }
+
+    /// directory, written and skipped frames of the running recording
+    pub fn recording(&self) -> Option<(PathBuf, usize, usize)> {
+        let recorder = self.recorder.lock().unwrap();
+        let recorder = recorder.as_ref()?;
+        Some((
+            recorder.dir().to_path_buf(),
+            recorder.written(),
+            recorder.skipped(),
+        ))
+    }
+
+    pub fn set_bracket(&mut self, bracket: Option<Vec<Exposure>>) {
+        self.bracket = bracket
+    }
+
+    pub fn bracket(&self) -> Option<&[Exposure]> {
+        self.bracket.as_deref()
+    }
+
+    pub fn corrections(&self) -> MutexGuard<'_, Corrections> {
+        self.corrections.lock().unwrap()
+    }
+
+    pub fn name(&self) -> Option<String> {
+        Some(self.acquisition.as_ref()?.name.clone())
+    }
+}
+
+impl Drop for CameraStream {
+    fn drop(&mut self) {
+        self.close()
     }
+}
+
+/// state of the stream that is used by the acquisition thread
+struct Shared {
+    corrections: Arc<Mutex<Corrections>>,
+    frames: Arc<FrameBuffer>,
+    exposure: Arc<Mutex<ExposureTag>>,
+    roi: Arc<Mutex<Option<Roi>>>,
+    dropped: Arc<AtomicU64>,
+    recorder: Arc<Mutex<Option<Recorder>>>,
+}
 
-    pub fn is_open() -> bool {
-        CAMERA_STREAM.lock().unwrap().is_some()
+fn acquire(
+    mut source: Box<dyn FrameSource>,
+    running: Arc<AtomicBool>,
+    lost: Arc<AtomicBool>,
+    shared: Shared,
+) {
+    let mut failures = 0;
+    let mut last_stamp = None;
+    while running.load(Ordering::Relaxed) {
+        match source.next_frame() {
+            Ok(mut img) => {
+                failures = 0;
+                if let (Some(stamp), Some(last)) = (img.stamp, last_stamp) {
+                    let gap = frame_buffer::dropped_between(last, stamp);
+                    if gap > 0 {
+                        warn!("the driver dropped {} frames", gap);
+                        shared.dropped.fetch_add(gap, Ordering::Relaxed);
+                    }
+                }
+                last_stamp = img.stamp;
+                if let Some(roi) = *shared.roi.lock().unwrap() {
+                    img = img.cropped(roi);
+                }
+                {
+                    let mut tag = shared.exposure.lock().unwrap();
+                    if tag.settle > 0 {
+                        tag.settle -= 1;
+                    } else if let Some(exposure) = tag.exposure {
+                        // recorded frames already carry the exposure they were taken with
+                        img.exposure = Some(exposure);
+                    }
+                }
+                if let Some(recorder) = shared.recorder.lock().unwrap().as_mut() {
+                    recorder.record(&img);
+                }
+                shared.corrections.lock().unwrap().apply(&mut img);
+                shared.frames.push(img);
+            }
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                info!("{}", err);
+                break;
+            }
+            // the source is waiting for data and only returned to check if it is still running
+            Err(err) if err.kind() == ErrorKind::TimedOut => (),
+            Err(err) => {
+                failures += 1;
+                let unplugged = matches!(err.raw_os_error(), Some(ENODEV) | Some(ENXIO));
+                if unplugged || failures >= MAX_FAILURES {
+                    error!("lost {}: {}", source.name(), err);
+                    lost.store(true, Ordering::Relaxed);
+                    break;
+                }
+                error!("could not get frame: {}", err);
+                thread::sleep(ERROR_BACKOFF)
+            }
+        }
     }
 }
@@ -0,0 +1,242 @@
+use egui::{DragValue, Slider, Ui};
+use log::{error, warn};
+use v4l::{control, prelude::*, Control};
+
+/// Reads the description and current value of every control of the camera.
+pub fn fetch_controls(camera: &Device) -> std::io::Result<Vec<(control::Description, Control)>> {
+    let ctrl_description = camera.query_controls()?;
+    let mut controls = Vec::new();
+    for d in ctrl_description {
+        // there is nothing to read from these
+        let value = match d.typ {
+            control::Type::Button | control::Type::CtrlClass => Ok(Control {
+                id: d.id,
+                value: control::Value::None,
+            }),
+            _ if d.flags.contains(control::Flags::WRITE_ONLY) => Ok(Control {
+                id: d.id,
+                value: control::Value::None,
+            }),
+            _ => camera.control(d.id),
+        };
+        match value {
+            Ok(control) => controls.push((d, control)),
+            Err(err) => warn!(
+                "failed to load value for {}, id: {}, type: {}, disregarding it. Err:{}",
+                d.name, d.id, d.typ, err
+            ),
+        }
+    }
+    Ok(controls)
+}
+
+/// Shows an editor fitting the type of the control and writes changes to the camera
+/// right away, streaming continues. Returns true if a value was written.
+pub fn control_ui(
+    ui: &mut Ui,
+    description: &control::Description,
+    control: &mut Control,
+    cam: &Device,
+) -> bool {
+    if description.typ == control::Type::CtrlClass {
+        ui.separator();
+        ui.heading(&description.name);
+        return false;
+    }
+    ui.strong(&description.name);
+    let read_only = description
+        .flags
+        .intersects(control::Flags::READ_ONLY | control::Flags::DISABLED);
+    if description.flags.contains(control::Flags::INACTIVE) {
+        ui.label("inactive, another control currently overrides it");
+    }
+
+    let mut new = None;
+    ui.add_enabled_ui(!read_only, |ui| {
+        new = value_ui(ui, description, &control.value);
+    });
+    let value = match new {
+        Some(value) => value,
+        None => return false,
+    };
+    let written = match &value {
+        control::Value::String(text) => control::Value::String(format!("{}\0", text)),
+        control::Value::Integer(val) => control::Value::Integer(*val),
+        control::Value::Boolean(val) => control::Value::Boolean(*val),
+        _ => control::Value::None,
+    };
+    match cam.set_control(Control {
+        id: description.id,
+        value: written,
+    }) {
+        Ok(_) => {
+            if description.typ != control::Type::Button {
+                control.value = value;
+            }
+            true
+        }
+        Err(err) => {
+            error!("could not set {}: {}", description.name, err);
+            false
+        }
+    }
+}
+
+/// returns the new value if the user changed it
+fn value_ui(
+    ui: &mut Ui,
+    description: &control::Description,
+    value: &control::Value,
+) -> Option<control::Value> {
+    let control::Description {
+        typ,
+        minimum,
+        maximum,
+        step,
+        default,
+        ..
+    } = description;
+    match (typ, value) {
+        (control::Type::Integer, control::Value::Integer(val)) => {
+            let mut val = *val;
+            ui.horizontal(|ui| {
+                let released = ui
+                    .add(
+                        Slider::new(&mut val, *minimum..=*maximum)
+                            .clamp_to_range(true)
+                            .integer()
+                            .step_by(*step as f64),
+                    )
+                    .drag_released();
+                let reset = reset_button(ui, val, *default);
+                match (released, reset) {
+                    (_, true) => Some(control::Value::Integer(*default)),
+                    (true, false) => Some(control::Value::Integer(val)),
+                    _ => None,
+                }
+            })
+            .inner
+        }
+        (control::Type::Integer64, control::Value::Integer(val)) => {
+            let mut val = *val;
+            ui.horizontal(|ui| {
+                let response = ui.add(
+                    DragValue::new(&mut val)
+                        .clamp_range(*minimum..=*maximum)
+                        .speed((*step).max(1) as f64),
+                );
+                let reset = reset_button(ui, val, *default);
+                match (response.drag_released() || response.lost_focus(), reset) {
+                    (_, true) => Some(control::Value::Integer(*default)),
+                    (true, false) => Some(control::Value::Integer(val)),
+                    _ => None,
+                }
+            })
+            .inner
+        }
+        (control::Type::Boolean, control::Value::Boolean(b)) => {
+            let mut b = *b;
+            ui.checkbox(&mut b, "")
+                .changed()
+                .then_some(control::Value::Boolean(b))
+        }
+        // some drivers report boolean controls as integers
+        (control::Type::Boolean, control::Value::Integer(val)) => {
+            let mut b = *val != 0;
+            ui.checkbox(&mut b, "")
+                .changed()
+                .then_some(control::Value::Integer(b as i64))
+        }
+        (control::Type::Menu | control::Type::IntegerMenu, control::Value::Integer(val)) => {
+            let items = description.items.as_deref().unwrap_or_default();
+            let label = |index: i64| {
+                items
+                    .iter()
+                    .find(|(i, _)| *i as i64 == index)
+                    .map(|(_, item)| item.to_string())
+                    .unwrap_or_else(|| index.to_string())
+            };
+            let mut selected = *val;
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_id_source(description.id)
+                    .selected_text(label(selected))
+                    .show_ui(ui, |ui| {
+                        for (index, item) in items {
+                            ui.selectable_value(&mut selected, *index as i64, item.to_string());
+                        }
+                    });
+                let reset = reset_button(ui, selected, *default);
+                match (selected != *val, reset) {
+                    (_, true) => Some(control::Value::Integer(*default)),
+                    (true, false) => Some(control::Value::Integer(selected)),
+                    _ => None,
+                }
+            })
+            .inner
+        }
+        (control::Type::Bitmask, control::Value::Integer(val)) => {
+            let mut mask = *val;
+            ui.horizontal_wrapped(|ui| {
+                for bit in 0..32 {
+                    if *maximum & (1 << bit) == 0 {
+                        continue;
+                    }
+                    let mut set = mask & (1 << bit) != 0;
+                    if ui.checkbox(&mut set, bit.to_string()).changed() {
+                        mask ^= 1 << bit;
+                    }
+                }
+            });
+            (mask != *val).then_some(control::Value::Integer(mask))
+        }
+        (control::Type::Button, _) => ui
+            .button("execute")
+            .clicked()
+            .then_some(control::Value::None),
+        (control::Type::String, control::Value::String(text)) => {
+            // the text being edited is kept until it is submitted with enter
+            let id = ui.id().with(description.id);
+            let mut edited = ui
+                .data()
+                .get_temp::<String>(id)
+                .unwrap_or_else(|| text.trim_end_matches('\0').to_string());
+            let response = ui.text_edit_singleline(&mut edited);
+            if response.lost_focus() && ui.input().key_pressed(egui::Key::Enter) {
+                ui.data().remove::<String>(id);
+                edited.truncate(*maximum as usize);
+                Some(control::Value::String(edited))
+            } else {
+                if response.changed() {
+                    ui.data().insert_temp(id, edited);
+                }
+                None
+            }
+        }
+        (_, control::Value::CompoundU8(vals)) => compound_label(ui, vals.len()),
+        (_, control::Value::CompoundU16(vals)) => compound_label(ui, vals.len()),
+        (_, control::Value::CompoundU32(vals)) => compound_label(ui, vals.len()),
+        (_, control::Value::CompoundPtr(vals)) => compound_label(ui, vals.len()),
+        (_, control::Value::None) => {
+            ui.label("the value cannot be read");
+            None
+        }
+        (typ, value) => {
+            ui.label(format!("unexpected value {:?} for type {}", value, typ));
+            None
+        }
+    }
+}
+
+fn reset_button(ui: &mut Ui, val: i64, default: i64) -> bool {
+    ui.add_enabled(val != default, egui::Button::new("↻"))
+        .on_hover_text(format!("reset to {}", default))
+        .clicked()
+}
+
+fn compound_label(ui: &mut Ui, len: usize) -> Option<control::Value> {
+    ui.label(format!(
+        "compound value with {} elements, not editable",
+        len
+    ));
+    None
+}
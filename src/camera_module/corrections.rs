@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+
+use egui::{DragValue, Slider, Ui};
+use log::{info, warn};
+
+use super::{
+    bad_pixels::{self, BadPixelMask, TransientDetector},
+    Crop, Exposure, Image,
+};
+
+/// radius of the box blur used to separate the pixel response from large scale structure
+const FLAT_SMOOTHING_RADIUS: usize = 8;
+/// flat field values below this are considered dead and not corrected
+const MIN_FLAT: f32 = 0.05;
+
+/// Per pixel corrections applied to every frame before it reaches the other modules.
+pub struct Corrections {
+    dark: Option<MeanFrame>,
+    subtract_dark: bool,
+    /// the exposure of the frames differs from the dark frame, which is then not subtracted
+    dark_mismatch: bool,
+    flat: Option<MeanFrame>,
+    divide_flat: bool,
+    pixel_response_only: bool,
+    capture: Option<Capture>,
+    capture_count: usize,
+    /// bad pixel masks keyed by the camera, see [`super::FrameSource::key`]
+    masks: HashMap<String, BadPixelMask>,
+    camera: Option<String>,
+    mask_bad_pixels: bool,
+    detect_hot_pixels: bool,
+    hot_pixel_threshold: f32,
+    detect_transients: bool,
+    transient_threshold: f32,
+    transients: TransientDetector,
+}
+
+impl Default for Corrections {
+    fn default() -> Self {
+        Self {
+            dark: None,
+            subtract_dark: true,
+            dark_mismatch: false,
+            flat: None,
+            divide_flat: true,
+            pixel_response_only: false,
+            capture: None,
+            capture_count: 20,
+            masks: HashMap::new(),
+            camera: None,
+            mask_bad_pixels: true,
+            detect_hot_pixels: true,
+            hot_pixel_threshold: 8.0,
+            detect_transients: false,
+            transient_threshold: 0.1,
+            transients: TransientDetector::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CaptureKind {
+    Dark,
+    Flat,
+}
+
+impl Corrections {
+    pub fn apply(&mut self, img: &mut Image) {
+        self.capture_frame(img, CaptureKind::Dark);
+
+        if self.subtract_dark {
+            if let Some(dark) = self.dark.as_ref() {
+                if !dark.fits(img) {
+                    warn!("dark frame does not fit the image size or region, discarding it");
+                    self.dark = None;
+                } else if dark.exposure != img.exposure {
+                    // the dark current grows with the exposure time but the bias does not, so
+                    // the dark frame can't simply be scaled
+                    if !self.dark_mismatch {
+                        warn!(
+                            "dark frame was taken at {}, not subtracting it from frames at {}",
+                            describe(dark.exposure),
+                            describe(img.exposure)
+                        );
+                        self.dark_mismatch = true;
+                    }
+                } else {
+                    self.dark_mismatch = false;
+                    for (val, dark) in img.data.iter_mut().zip(dark.mean.iter()) {
+                        // saturated samples are left alone, their true value is unknown anyway
+                        if *val != u16::MAX {
+                            *val = (*val as f32 - dark).round().max(0.0) as u16;
+                        }
+                    }
+                    img.texture = None;
+                }
+            }
+        }
+
+        self.capture_frame(img, CaptureKind::Flat);
+
+        if self.divide_flat {
+            if let Some(flat) = self.flat.as_ref() {
+                if flat.fits(img) {
+                    for (val, flat) in img.data.iter_mut().zip(flat.mean.iter()) {
+                        if *val != u16::MAX {
+                            // stay below the maximum, so the sample is not mistaken as saturated
+                            *val = (*val as f32 / flat).round().min(u16::MAX as f32 - 1.0) as u16;
+                        }
+                    }
+                    img.texture = None;
+                } else {
+                    warn!("flat field does not fit the image size or region, discarding it");
+                    self.flat = None;
+                }
+            }
+        }
+
+        let mut bad_pixels = None;
+        if self.mask_bad_pixels {
+            if let Some(mask) = self.mask() {
+                if mask.fits(img) {
+                    bad_pixels = Some(mask.to_dense(img));
+                }
+            }
+        }
+        if self.detect_transients {
+            let transient = self.transients.detect(img, self.transient_threshold);
+            match bad_pixels.as_mut() {
+                Some(bad) => bad
+                    .iter_mut()
+                    .zip(transient)
+                    .for_each(|(bad, transient)| *bad |= transient),
+                None => bad_pixels = Some(transient),
+            }
+        }
+        img.bad_pixels = bad_pixels;
+    }
+
+    /// selects the bad pixel mask of the camera with this key
+    pub fn set_camera(&mut self, key: String, name: &str) {
+        // earlier versions stored the masks by the name, which identical cameras share
+        if !self.masks.contains_key(&key) {
+            if let Some(mask) = self.masks.remove(name) {
+                info!("moved the bad pixel mask of {} to {}", name, key);
+                self.masks.insert(key.clone(), mask);
+            }
+        }
+        self.camera = Some(key);
+        self.transients = TransientDetector::default();
+    }
+
+    /// a dark frame is subtracted, which only fits frames at the exposure it was taken at
+    pub fn subtracts_dark(&self) -> bool {
+        self.subtract_dark && self.dark.is_some()
+    }
+
+    pub fn masks(&self) -> &HashMap<String, BadPixelMask> {
+        &self.masks
+    }
+
+    pub fn set_masks(&mut self, masks: HashMap<String, BadPixelMask>) {
+        self.masks = masks
+    }
+
+    fn mask(&self) -> Option<&BadPixelMask> {
+        self.masks.get(self.camera.as_ref()?)
+    }
+
+    fn capture_frame(&mut self, img: &Image, kind: CaptureKind) {
+        let capture = match self.capture.as_mut() {
+            Some(capture) if capture.kind == kind => capture,
+            _ => return,
+        };
+        if let Some(frame) = capture.add(img) {
+            info!("captured {:?} frame from {} frames", kind, capture.count);
+            match kind {
+                CaptureKind::Dark => {
+                    if self.detect_hot_pixels {
+                        let (mean, std) = capture.pixel_stats();
+                        let detected = bad_pixels::detect_hot_pixels(
+                            &mean,
+                            &std,
+                            frame.width,
+                            frame.height,
+                            frame.crop,
+                            self.hot_pixel_threshold,
+                        );
+                        info!("detected {} hot pixels", detected.len());
+                        match self.camera.clone() {
+                            Some(camera) => self.masks.entry(camera).or_default().merge(detected),
+                            None => warn!("no camera selected, the hot pixels are not saved"),
+                        }
+                    }
+                    self.dark = Some(frame);
+                    self.dark_mismatch = false;
+                }
+                CaptureKind::Flat => self.flat = Some(frame.normalised(self.pixel_response_only)),
+            }
+            self.capture = None;
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.strong("Corrections");
+        if let Some(capture) = self.capture.as_ref() {
+            ui.label(format!(
+                "capturing {:?} frame {}/{}",
+                capture.kind, capture.captured, capture.count
+            ));
+            if ui.button("cancel").clicked() {
+                self.capture = None;
+            }
+            return;
+        }
+        ui.add(
+            DragValue::new(&mut self.capture_count)
+                .clamp_range(1..=1000)
+                .prefix("average over ")
+                .suffix(" frames"),
+        );
+
+        if ui
+            .button("capture dark frame")
+            .on_hover_text("block the light source while capturing")
+            .clicked()
+        {
+            self.capture = Some(Capture::new(CaptureKind::Dark, self.capture_count));
+        }
+        if let Some(dark) = self.dark.as_ref() {
+            ui.checkbox(&mut self.subtract_dark, "subtract dark frame")
+                .on_hover_text(format!("taken at {}", describe(dark.exposure)));
+            if self.subtract_dark && self.dark_mismatch {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    "the exposure changed, capture a new dark frame",
+                );
+            }
+            if ui.button("discard dark frame").clicked() {
+                self.dark = None;
+            }
+        }
+
+        if ui
+            .button("capture flat field")
+            .on_hover_text("illuminate the sensor uniformly or with a broadband lamp")
+            .clicked()
+        {
+            self.capture = Some(Capture::new(CaptureKind::Flat, self.capture_count));
+        }
+        ui.checkbox(&mut self.pixel_response_only, "pixel response only")
+            .on_hover_text(
+                "removes large scale structure from the flat field, \
+                use this for a lamp spectrum so its shape is not divided out",
+            );
+        if self.flat.is_some() {
+            ui.checkbox(&mut self.divide_flat, "divide by flat field");
+            if ui.button("discard flat field").clicked() {
+                self.flat = None;
+            }
+        }
+
+        ui.strong("Bad pixels");
+        let count = self.mask().map(|mask| mask.len()).unwrap_or(0);
+        ui.label(format!("{} pixels are masked for this camera", count));
+        ui.checkbox(&mut self.mask_bad_pixels, "mask bad pixels");
+        ui.checkbox(
+            &mut self.detect_hot_pixels,
+            "detect hot pixels in dark frames",
+        );
+        if self.detect_hot_pixels {
+            ui.add(Slider::new(&mut self.hot_pixel_threshold, 3.0..=30.0).text("threshold in σ"));
+        }
+        let has_mask = self.mask().map(|mask| !mask.is_empty()).unwrap_or(false);
+        if has_mask && ui.button("clear mask").clicked() {
+            if let Some(camera) = self.camera.as_ref() {
+                self.masks.remove(camera);
+            }
+        }
+        ui.checkbox(&mut self.detect_transients, "detect cosmic rays")
+            .on_hover_text("masks single pixels that are much brighter than in the last frame");
+        if self.detect_transients {
+            ui.add(
+                Slider::new(&mut self.transient_threshold, 0.01..=1.0)
+                    .logarithmic(true)
+                    .text("threshold"),
+            );
+        }
+    }
+}
+
+/// per sample mean of several frames, in the same units as [`Image::data`]
+struct MeanFrame {
+    width: usize,
+    height: usize,
+    crop: Option<Crop>,
+    /// exposure of the averaged frames
+    exposure: Option<Exposure>,
+    mean: Vec<f32>,
+}
+
+impl MeanFrame {
+    fn fits(&self, img: &Image) -> bool {
+        self.width == img.width && self.height == img.height && self.crop == img.crop
+    }
+
+    /// scales every channel to a mean of one, if `pixel_response_only` is set every sample is
+    /// instead divided by the smoothed flat so only pixel to pixel variations remain
+    fn normalised(mut self, pixel_response_only: bool) -> Self {
+        let reference = if pixel_response_only {
+            self.smoothed(FLAT_SMOOTHING_RADIUS)
+        } else {
+            let mut means = [0.0; 3];
+            for (channel, mean) in means.iter_mut().enumerate() {
+                // photosites of other colours are zero in raw images and don't count
+                let (sum, count) = self
+                    .mean
+                    .iter()
+                    .skip(channel)
+                    .step_by(3)
+                    .filter(|val| **val > 0.0)
+                    .fold((0.0, 0), |(sum, count), val| (sum + val, count + 1));
+                *mean = if count == 0 { 1.0 } else { sum / count as f32 };
+            }
+            (0..self.mean.len()).map(|i| means[i % 3]).collect()
+        };
+        for (val, reference) in self.mean.iter_mut().zip(reference) {
+            let normed = *val / reference;
+            *val = if normed.is_finite() && normed > MIN_FLAT {
+                normed
+            } else {
+                1.0
+            };
+        }
+        self
+    }
+
+    /// separable box blur of every channel, ignoring zero samples
+    fn smoothed(&self, radius: usize) -> Vec<f32> {
+        let (width, height) = (self.width, self.height);
+        let blur = |data: &[f32], horizontal: bool| {
+            let mut out = vec![0.0; data.len()];
+            for y in 0..height {
+                for x in 0..width {
+                    for channel in 0..3 {
+                        let (from, to) = if horizontal {
+                            (x.saturating_sub(radius), (x + radius + 1).min(width))
+                        } else {
+                            (y.saturating_sub(radius), (y + radius + 1).min(height))
+                        };
+                        let (sum, count) = (from..to)
+                            .map(|i| {
+                                let (x, y) = if horizontal { (i, y) } else { (x, i) };
+                                data[3 * (y * width + x) + channel]
+                            })
+                            .filter(|val| *val > 0.0)
+                            .fold((0.0, 0), |(sum, count), val| (sum + val, count + 1));
+                        out[3 * (y * width + x) + channel] =
+                            if count == 0 { 0.0 } else { sum / count as f32 };
+                    }
+                }
+            }
+            out
+        };
+        blur(&blur(&self.mean, true), false)
+    }
+}
+
+fn describe(exposure: Option<Exposure>) -> String {
+    match exposure {
+        Some(exposure) => exposure.to_string(),
+        None => "an unknown exposure".to_string(),
+    }
+}
+
+struct Capture {
+    kind: CaptureKind,
+    count: usize,
+    captured: usize,
+    size: Option<(usize, usize)>,
+    crop: Option<Crop>,
+    exposure: Option<Exposure>,
+    sum: Vec<f64>,
+    /// sum of the squared pixel values, where a pixel value is the sum of its channels
+    pixel_sum_sq: Vec<f64>,
+}
+
+impl Capture {
+    fn new(kind: CaptureKind, count: usize) -> Self {
+        Self {
+            kind,
+            count,
+            captured: 0,
+            size: None,
+            crop: None,
+            exposure: None,
+            sum: Vec::new(),
+            pixel_sum_sq: Vec::new(),
+        }
+    }
+
+    /// mean and standard deviation of every pixel over all captured frames
+    fn pixel_stats(&self) -> (Vec<f32>, Vec<f32>) {
+        let n = self.captured.max(1) as f64;
+        self.sum
+            .chunks_exact(3)
+            .zip(self.pixel_sum_sq.iter())
+            .map(|(sum, sum_sq)| {
+                let mean = sum.iter().sum::<f64>() / n;
+                let var = (sum_sq / n - mean * mean).max(0.0);
+                (mean as f32, var.sqrt() as f32)
+            })
+            .unzip()
+    }
+
+    /// returns the mean once enough frames were added
+    fn add(&mut self, img: &Image) -> Option<MeanFrame> {
+        if self.size != Some((img.width, img.height))
+            || self.crop != img.crop
+            || self.exposure != img.exposure
+        {
+            // start over if the image size, region or exposure changed during capture
+            self.size = Some((img.width, img.height));
+            self.crop = img.crop;
+            self.exposure = img.exposure;
+            self.captured = 0;
+            self.sum = vec![0.0; img.data.len()];
+            self.pixel_sum_sq = vec![0.0; img.width * img.height];
+        }
+        for (sum, val) in self.sum.iter_mut().zip(img.data.iter()) {
+            *sum += *val as f64;
+        }
+        for (sum_sq, val) in self
+            .pixel_sum_sq
+            .iter_mut()
+            .zip(bad_pixels::pixel_values(img))
+        {
+            *sum_sq += val as f64 * val as f64;
+        }
+        self.captured += 1;
+        if self.captured < self.count {
+            return None;
+        }
+        let (width, height) = self.size.expect("size was set above");
+        Some(MeanFrame {
+            width,
+            height,
+            crop: self.crop,
+            exposure: self.exposure,
+            mean: self
+                .sum
+                .iter()
+                .map(|sum| (sum / self.captured as f64) as f32)
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DARK: u16 = 1000;
+
+    fn frame(val: u16, exposure: Option<Exposure>) -> Image {
+        let mut img = Image::new(4, 3, vec![val; 4 * 3 * 3], 16);
+        img.exposure = exposure;
+        img
+    }
+
+    fn exposure(time: i64) -> Option<Exposure> {
+        Some(Exposure { time, gain: None })
+    }
+
+    /// corrections holding a dark frame of `DARK` taken at `exposure`
+    fn with_dark(exposure: Option<Exposure>) -> Corrections {
+        let mut corrections = Corrections {
+            capture_count: 3,
+            detect_hot_pixels: false,
+            ..Corrections::default()
+        };
+        corrections.capture = Some(Capture::new(CaptureKind::Dark, corrections.capture_count));
+        for _ in 0..corrections.capture_count {
+            corrections.apply(&mut frame(DARK, exposure));
+        }
+        assert!(corrections.dark.is_some());
+        corrections
+    }
+
+    #[test]
+    fn dark_frame_is_subtracted_at_its_exposure() {
+        let mut corrections = with_dark(exposure(100));
+        let mut img = frame(5000, exposure(100));
+        corrections.apply(&mut img);
+        assert!(img.data.iter().all(|val| *val == 5000 - DARK));
+    }
+
+    #[test]
+    fn dark_frame_is_skipped_at_other_exposures() {
+        let mut corrections = with_dark(exposure(100));
+        for other in [exposure(400), None] {
+            let mut img = frame(5000, other);
+            corrections.apply(&mut img);
+            assert!(img.data.iter().all(|val| *val == 5000));
+            assert!(corrections.dark_mismatch);
+        }
+        // it is kept for when the exposure changes back
+        let mut img = frame(5000, exposure(100));
+        corrections.apply(&mut img);
+        assert!(img.data.iter().all(|val| *val == 5000 - DARK));
+        assert!(!corrections.dark_mismatch);
+    }
+
+    #[test]
+    fn capture_restarts_when_the_exposure_changes() {
+        let mut corrections = Corrections {
+            capture_count: 2,
+            detect_hot_pixels: false,
+            ..Corrections::default()
+        };
+        corrections.capture = Some(Capture::new(CaptureKind::Dark, 2));
+        corrections.apply(&mut frame(3000, exposure(400)));
+        corrections.apply(&mut frame(DARK, exposure(100)));
+        assert!(corrections.dark.is_none());
+        corrections.apply(&mut frame(DARK, exposure(100)));
+        let dark = corrections.dark.as_ref().unwrap();
+        assert_eq!(dark.exposure, exposure(100));
+        assert!(dark.mean.iter().all(|val| *val == DARK as f32));
+    }
+}
@@ -0,0 +1,277 @@
+use std::io::{Error, ErrorKind, Result};
+
+use image::ImageFormat;
+use v4l::{Format, FourCC};
+
+use super::{CfaPattern, Image};
+
+/// Pixel formats that can be converted to an [`Image`], in order of preference.
+/// High bit depth formats come first as they keep the dynamic range of the sensor.
+pub const SUPPORTED_FORMATS: [&[u8; 4]; 9] = [
+    b"Y16 ", b"Y12 ", b"Y10 ", b"RGB3", b"YUYV", b"MJPG", b"JPEG", b"NV12", b"GREY",
+];
+
+/// Raw bayer formats as (fourcc, bit depth, pattern), 8 bit formats are packed,
+/// all others use little endian 16 bit containers.
+pub const BAYER_FORMATS: [(&[u8; 4], u8, CfaPattern); 16] = [
+    (b"RGGB", 8, CfaPattern::Rggb),
+    (b"BA81", 8, CfaPattern::Bggr),
+    (b"GRBG", 8, CfaPattern::Grbg),
+    (b"GBRG", 8, CfaPattern::Gbrg),
+    (b"RG10", 10, CfaPattern::Rggb),
+    (b"BG10", 10, CfaPattern::Bggr),
+    (b"BA10", 10, CfaPattern::Grbg),
+    (b"GB10", 10, CfaPattern::Gbrg),
+    (b"RG12", 12, CfaPattern::Rggb),
+    (b"BG12", 12, CfaPattern::Bggr),
+    (b"BA12", 12, CfaPattern::Grbg),
+    (b"GB12", 12, CfaPattern::Gbrg),
+    (b"RG16", 16, CfaPattern::Rggb),
+    (b"BYR2", 16, CfaPattern::Bggr),
+    (b"GR16", 16, CfaPattern::Grbg),
+    (b"GB16", 16, CfaPattern::Gbrg),
+];
+
+pub fn is_supported(fourcc: FourCC) -> bool {
+    SUPPORTED_FORMATS.iter().any(|repr| **repr == fourcc.repr) || bayer_format(fourcc).is_some()
+}
+
+/// bit depth and pattern of a raw bayer format
+pub fn bayer_format(fourcc: FourCC) -> Option<(u8, CfaPattern)> {
+    BAYER_FORMATS
+        .iter()
+        .find(|(repr, _, _)| **repr == fourcc.repr)
+        .map(|(_, bit_depth, cfa)| (*bit_depth, *cfa))
+}
+
+/// Converts a raw frame as delivered by the driver into an rgb image.
+pub fn decode(buf: &[u8], format: &Format) -> Result<Image> {
+    let width = format.width as usize;
+    let height = format.height as usize;
+    if width == 0 || height == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{}x{} frame has no pixels", width, height),
+        ));
+    }
+    // both formats share the chroma between horizontal pairs of pixels
+    if matches!(&format.fourcc.repr, b"YUYV" | b"NV12") && width % 2 != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{} frames need an even width, got {}", format.fourcc, width),
+        ));
+    }
+    if let Some((bit_depth, cfa)) = bayer_format(format.fourcc) {
+        return decode_bayer(buf, format, bit_depth, cfa);
+    }
+    let rgb: Vec<u8> = match &format.fourcc.repr {
+        b"Y16 " => return decode_grey16(buf, format, 16),
+        b"Y12 " => return decode_grey16(buf, format, 12),
+        b"Y10 " => return decode_grey16(buf, format, 10),
+        b"RGB3" => {
+            let rows = rows(buf, format, width * 3, height)?;
+            rows.flat_map(|row| row.iter().copied()).collect()
+        }
+        b"GREY" => {
+            let rows = rows(buf, format, width, height)?;
+            rows.flat_map(|row| row.iter().flat_map(|y| [*y, *y, *y]))
+                .collect()
+        }
+        b"YUYV" => {
+            let rows = rows(buf, format, width * 2, height)?;
+            let mut rgb = Vec::with_capacity(width * height * 3);
+            for row in rows {
+                for pair in row.chunks_exact(4) {
+                    let (y0, u, y1, v) = (pair[0], pair[1], pair[2], pair[3]);
+                    rgb.extend_from_slice(&yuv_to_rgb(y0, u, v));
+                    rgb.extend_from_slice(&yuv_to_rgb(y1, u, v));
+                }
+            }
+            rgb
+        }
+        b"NV12" => {
+            let stride = stride(format, width);
+            let luma_len = stride * height;
+            let rows = rows(buf, format, width, height)?;
+            // an odd last row shares the chroma row of the row above
+            let chroma_rows = (height + 1) / 2;
+            let chroma = buf
+                .get(luma_len..)
+                .filter(|chroma| chroma.len() >= stride * (chroma_rows - 1) + width)
+                .ok_or_else(|| too_short(buf, format))?;
+            let mut rgb = Vec::with_capacity(width * height * 3);
+            for (y, row) in rows.enumerate() {
+                let chroma_row = &chroma[(y / 2) * stride..];
+                for (x, luma) in row.iter().enumerate() {
+                    let u = chroma_row[x & !1];
+                    let v = chroma_row[(x & !1) + 1];
+                    rgb.extend_from_slice(&yuv_to_rgb(*luma, u, v));
+                }
+            }
+            rgb
+        }
+        b"MJPG" | b"JPEG" => {
+            let img = image::load_from_memory_with_format(buf, ImageFormat::Jpeg)
+                .map_err(|err| Error::new(ErrorKind::Other, err))?
+                .into_rgb8();
+            if img.width() != format.width || img.height() != format.height {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "decoded jpeg has size {}x{}, expected {}x{}",
+                        img.width(),
+                        img.height(),
+                        format.width,
+                        format.height
+                    ),
+                ));
+            }
+            return Ok(img.into());
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("pixel format {} is not supported", format.fourcc),
+            ))
+        }
+    };
+    Ok(Image::new(
+        width,
+        height,
+        rgb.into_iter().map(|val| val as u16).collect(),
+        8,
+    ))
+}
+
+/// little endian 16 bit containers of which the lowest `bit_depth` bits are used
+fn decode_grey16(buf: &[u8], format: &Format, bit_depth: u8) -> Result<Image> {
+    let width = format.width as usize;
+    let height = format.height as usize;
+    let mask = (u16::MAX as u32 >> (16 - bit_depth as u32)) as u16;
+    let rows = rows(buf, format, width * 2, height)?;
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for row in rows {
+        for sample in row.chunks_exact(2) {
+            let val = u16::from_le_bytes([sample[0], sample[1]]) & mask;
+            rgb.extend_from_slice(&[val, val, val]);
+        }
+    }
+    Ok(Image::new(width, height, rgb, bit_depth))
+}
+
+fn decode_bayer(buf: &[u8], format: &Format, bit_depth: u8, cfa: CfaPattern) -> Result<Image> {
+    let width = format.width as usize;
+    let height = format.height as usize;
+    let mosaic = if bit_depth == 8 {
+        let rows = rows(buf, format, width, height)?;
+        rows.flat_map(|row| row.iter().map(|val| *val as u16))
+            .collect()
+    } else {
+        let mask = (u16::MAX as u32 >> (16 - bit_depth as u32)) as u16;
+        let rows = rows(buf, format, width * 2, height)?;
+        rows.flat_map(|row| {
+            row.chunks_exact(2)
+                .map(move |sample| u16::from_le_bytes([sample[0], sample[1]]) & mask)
+        })
+        .collect()
+    };
+    Ok(Image::new_raw(width, height, mosaic, bit_depth, cfa))
+}
+
+fn stride(format: &Format, row_len: usize) -> usize {
+    (format.stride as usize).max(row_len)
+}
+
+/// iterator over the rows of a packed format, each row is exactly `row_len` bytes long
+fn rows<'a>(
+    buf: &'a [u8],
+    format: &Format,
+    row_len: usize,
+    height: usize,
+) -> Result<impl Iterator<Item = &'a [u8]>> {
+    let stride = stride(format, row_len);
+    if height > 0 && buf.len() < stride * (height - 1) + row_len {
+        return Err(too_short(buf, format));
+    }
+    Ok(buf
+        .chunks(stride)
+        .take(height)
+        .map(move |row| &row[..row_len]))
+}
+
+fn too_short(buf: &[u8], format: &Format) -> Error {
+    // not `UnexpectedEof`, which would end the source instead of skipping the frame
+    Error::new(
+        ErrorKind::InvalidData,
+        format!(
+            "{} bytes are not enough for a {}x{} {} frame",
+            buf.len(),
+            format.width,
+            format.height,
+            format.fourcc
+        ),
+    )
+}
+
+/// BT.601 limited range conversion as used by most webcams
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let y = 1.164 * (y as f32 - 16.0);
+    let u = u as f32 - 128.0;
+    let v = v as f32 - 128.0;
+    [
+        (y + 1.596 * v).round().clamp(0.0, 255.0) as u8,
+        (y - 0.392 * u - 0.813 * v).round().clamp(0.0, 255.0) as u8,
+        (y + 2.017 * u).round().clamp(0.0, 255.0) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(width: u32, height: u32, fourcc: &[u8; 4]) -> Format {
+        Format::new(width, height, FourCC::new(fourcc))
+    }
+
+    fn invalid(result: Result<Image>) -> bool {
+        matches!(result, Err(err) if err.kind() == ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn odd_widths_of_subsampled_formats_are_rejected() {
+        assert!(invalid(decode(&[128; 3 * 2 * 2], &format(3, 2, b"YUYV"))));
+        assert!(invalid(decode(&[128; 3 * 2 * 2], &format(3, 2, b"NV12"))));
+    }
+
+    #[test]
+    fn nv12_with_an_odd_height_decodes() {
+        // three luma rows share two chroma rows
+        let img = decode(&[128; 4 * 3 + 4 * 2], &format(4, 3, b"NV12")).unwrap();
+        assert_eq!((img.width, img.height), (4, 3));
+        assert!(invalid(decode(&[128; 4 * 3 + 4], &format(4, 3, b"NV12"))));
+    }
+
+    #[test]
+    fn short_buffers_are_rejected() {
+        for (fourcc, bytes_per_pixel) in [
+            (b"RGB3", 3),
+            (b"GREY", 1),
+            (b"YUYV", 2),
+            (b"Y16 ", 2),
+            (b"RGGB", 1),
+            (b"RG10", 2),
+        ] {
+            let format = format(4, 4, fourcc);
+            let len = 4 * 4 * bytes_per_pixel;
+            assert!(decode(&vec![0; len], &format).is_ok());
+            assert!(invalid(decode(&vec![0; len - 1], &format)));
+            assert!(invalid(decode(&[], &format)));
+        }
+        assert!(invalid(decode(&[0; 4 * 4], &format(4, 4, b"NV12"))));
+    }
+
+    #[test]
+    fn empty_frames_are_rejected() {
+        assert!(invalid(decode(&[], &format(0, 4, b"GREY"))));
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use super::{FrameSource, Image};
+
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "bmp", "tif", "tiff"];
+/// time between two images, comparable to a slow webcam
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Plays back all images in a folder in alphabetical order and starts over at the end.
+pub struct FolderSource {
+    folder: PathBuf,
+    files: Vec<PathBuf>,
+    next: usize,
+    last_frame: Option<Instant>,
+}
+
+impl FolderSource {
+    pub fn new(folder: impl AsRef<Path>) -> Result<Self> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&folder)? {
+            let path = entry?.path();
+            let is_image = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                .unwrap_or(false);
+            if is_image {
+                files.push(path)
+            }
+        }
+        if files.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "the folder does not contain any images",
+            ));
+        }
+        files.sort();
+        Ok(Self {
+            folder: folder.as_ref().to_path_buf(),
+            files,
+            next: 0,
+            last_frame: None,
+        })
+    }
+}
+
+impl FrameSource for FolderSource {
+    fn name(&self) -> String {
+        format!("{} ({} images)", self.folder.display(), self.files.len())
+    }
+
+    fn next_frame(&mut self) -> Result<Image> {
+        if let Some(elapsed) = self.last_frame.map(|last| last.elapsed()) {
+            if elapsed < FRAME_INTERVAL {
+                std::thread::sleep(FRAME_INTERVAL - elapsed)
+            }
+        }
+        self.last_frame = Some(Instant::now());
+        let path = &self.files[self.next];
+        self.next = (self.next + 1) % self.files.len();
+        let img = image::open(path).map_err(|err| Error::new(ErrorKind::Other, err))?;
+        if img.color().bytes_per_pixel() / img.color().channel_count() > 1 {
+            Ok(img.into_rgb16().into())
+        } else {
+            Ok(img.into_rgb8().into())
+        }
+    }
+}
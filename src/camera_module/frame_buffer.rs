@@ -0,0 +1,201 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{DriverStamp, Image};
+
+/// A frame as delivered by the acquisition thread.
+#[derive(Clone)]
+pub struct Frame {
+    /// increases by one for every acquired frame, also across sources
+    pub sequence: u64,
+    /// time at which the frame was received
+    pub timestamp: Instant,
+    pub img: Arc<Image>,
+}
+
+impl Frame {
+    /// seconds since `earlier`, taken from the driver time stamps if both frames have one
+    pub fn seconds_since(&self, earlier: &Frame) -> f32 {
+        match (self.img.stamp, earlier.img.stamp) {
+            (Some(now), Some(before)) if now.timestamp >= before.timestamp => {
+                (now.timestamp - before.timestamp).as_secs_f32()
+            }
+            _ => self
+                .timestamp
+                .saturating_duration_since(earlier.timestamp)
+                .as_secs_f32(),
+        }
+    }
+
+    /// Frames missing between `earlier` and this one, whether the driver dropped them or
+    /// they were pushed out of the buffer before they were read.
+    pub fn dropped_since(&self, earlier: &Frame) -> u64 {
+        match (self.img.stamp, earlier.img.stamp) {
+            (Some(now), Some(before)) if now.sequence > before.sequence => {
+                dropped_between(before, now)
+            }
+            _ => self.sequence.saturating_sub(earlier.sequence + 1),
+        }
+    }
+}
+
+/// number of frames missing between two frames of the driver, a sequence that
+/// went backwards means the driver restarted counting
+pub fn dropped_between(earlier: DriverStamp, later: DriverStamp) -> u64 {
+    match later.sequence.checked_sub(earlier.sequence) {
+        Some(diff) => diff.saturating_sub(1) as u64,
+        None => 0,
+    }
+}
+
+/// Bounded ring buffer between the acquisition thread and the modules consuming the frames.
+/// Once full the oldest frame is dropped, consumers remember the last sequence number they saw.
+pub struct FrameBuffer {
+    capacity: usize,
+    inner: Mutex<Inner>,
+    new_frame: Condvar,
+}
+
+struct Inner {
+    frames: VecDeque<Frame>,
+    next_sequence: u64,
+}
+
+impl FrameBuffer {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            capacity,
+            inner: Mutex::new(Inner {
+                frames: VecDeque::with_capacity(capacity),
+                next_sequence: 0,
+            }),
+            new_frame: Condvar::new(),
+        }
+    }
+
+    pub fn push(&self, img: Image) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let sequence = inner.next_sequence;
+        inner.next_sequence += 1;
+        if inner.frames.len() == self.capacity {
+            inner.frames.pop_front();
+        }
+        inner.frames.push_back(Frame {
+            sequence,
+            timestamp: Instant::now(),
+            img: Arc::new(img),
+        });
+        self.new_frame.notify_all();
+        sequence
+    }
+
+    /// removes all frames, sequence numbers keep increasing
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().frames.clear()
+    }
+
+    pub fn latest(&self) -> Option<Frame> {
+        self.inner.lock().unwrap().frames.back().cloned()
+    }
+
+    /// time between the two newest frames
+    pub fn interval(&self) -> Option<Duration> {
+        let inner = self.inner.lock().unwrap();
+        let mut newest = inner.frames.iter().rev();
+        let last = newest.next()?;
+        let previous = newest.next()?;
+        Some(last.timestamp.saturating_duration_since(previous.timestamp))
+    }
+
+    /// all buffered frames that are newer than `sequence`, oldest first
+    pub fn since(&self, sequence: Option<u64>) -> Vec<Frame> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .frames
+            .iter()
+            .filter(|frame| sequence.map(|seq| frame.sequence > seq).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+
+    /// blocks until a frame newer than `sequence` is available or the timeout passed
+    pub fn wait_newer(&self, sequence: Option<u64>, timeout: Duration) -> Option<Frame> {
+        let is_newer = |inner: &Inner| {
+            inner
+                .frames
+                .back()
+                .filter(|frame| sequence.map(|seq| frame.sequence > seq).unwrap_or(true))
+                .cloned()
+        };
+        let inner = self.inner.lock().unwrap();
+        let (inner, _) = self
+            .new_frame
+            .wait_timeout_while(inner, timeout, |inner| is_newer(inner).is_none())
+            .unwrap();
+        is_newer(&inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(sequence: u32, millis: u64) -> DriverStamp {
+        DriverStamp {
+            sequence,
+            timestamp: Duration::from_millis(millis),
+        }
+    }
+
+    fn frame(stamp: Option<DriverStamp>) -> Image {
+        let mut img = Image::new(1, 1, vec![0; 3], 8);
+        img.stamp = stamp;
+        img
+    }
+
+    #[test]
+    fn gaps_in_the_driver_sequence_are_dropped_frames() {
+        assert_eq!(dropped_between(stamp(7, 0), stamp(8, 0)), 0);
+        assert_eq!(dropped_between(stamp(7, 0), stamp(11, 0)), 3);
+        // the driver started counting again
+        assert_eq!(dropped_between(stamp(7, 0), stamp(2, 0)), 0);
+    }
+
+    #[test]
+    fn buffer_keeps_the_newest_frames() {
+        let buffer = FrameBuffer::new(3);
+        for i in 0..5 {
+            buffer.push(frame(Some(stamp(i, 100 * i as u64))));
+        }
+        let frames = buffer.since(None);
+        assert_eq!(
+            frames
+                .iter()
+                .map(|frame| frame.sequence)
+                .collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+        assert_eq!(buffer.since(Some(3)).len(), 1);
+        assert_eq!(buffer.latest().unwrap().sequence, 4);
+        assert!(buffer
+            .wait_newer(Some(4), Duration::from_millis(1))
+            .is_none());
+    }
+
+    #[test]
+    fn frames_are_compared_by_the_driver_stamps() {
+        let buffer = FrameBuffer::new(4);
+        buffer.push(frame(Some(stamp(10, 1000))));
+        buffer.push(frame(Some(stamp(14, 1400))));
+        buffer.push(frame(None));
+        let frames = buffer.since(None);
+        assert_eq!(frames[1].dropped_since(&frames[0]), 3);
+        assert!((frames[1].seconds_since(&frames[0]) - 0.4).abs() < 1e-6);
+        // without a stamp only frames pushed out of the buffer can be counted
+        assert_eq!(frames[2].dropped_since(&frames[1]), 0);
+    }
+}
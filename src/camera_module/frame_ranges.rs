@@ -0,0 +1,152 @@
+use std::fmt;
+
+use v4l::{frameinterval, framesize, Fraction};
+
+/// denominator used when converting a frame interval in seconds back to a fraction
+const INTERVAL_DENOMINATOR: u32 = 10_000;
+
+/// Frame sizes of a camera that accepts any size within a range (stepwise or continuous).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeRange {
+    pub min_width: u32,
+    pub max_width: u32,
+    pub step_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+    pub step_height: u32,
+}
+
+impl SizeRange {
+    pub fn new(stepwise: &framesize::Stepwise) -> Self {
+        Self {
+            min_width: stepwise.min_width,
+            max_width: stepwise.max_width,
+            step_width: stepwise.step_width.max(1),
+            min_height: stepwise.min_height,
+            max_height: stepwise.max_height,
+            step_height: stepwise.step_height.max(1),
+        }
+    }
+
+    /// closest valid size
+    pub fn clamp(&self, width: u32, height: u32) -> (u32, u32) {
+        let snap = |val: u32, min: u32, max: u32, step: u32| {
+            let val = val.clamp(min, max);
+            let snapped = min + (val - min + step / 2) / step * step;
+            // rounding up may pass a maximum that is not on a step
+            match snapped > max {
+                true => snapped - step,
+                false => snapped,
+            }
+        };
+        (
+            snap(width, self.min_width, self.max_width, self.step_width),
+            snap(height, self.min_height, self.max_height, self.step_height),
+        )
+    }
+}
+
+impl fmt::Display for SizeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} to {}x{}",
+            self.min_width, self.min_height, self.max_width, self.max_height
+        )
+    }
+}
+
+/// Frame intervals in seconds of a camera that accepts any interval within a range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntervalRange {
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+}
+
+impl IntervalRange {
+    pub fn new(stepwise: &frameinterval::Stepwise) -> Self {
+        Self {
+            min: seconds(stepwise.min),
+            max: seconds(stepwise.max),
+            step: seconds(stepwise.step),
+        }
+    }
+
+    /// closest valid interval in seconds
+    pub fn snap(&self, interval: f64) -> f64 {
+        let interval = interval.clamp(self.min, self.max);
+        if self.step > 0.0 {
+            (self.min + ((interval - self.min) / self.step).round() * self.step).min(self.max)
+        } else {
+            interval
+        }
+    }
+}
+
+impl fmt::Display for IntervalRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.4} s to {:.1} s", self.min, self.max)
+    }
+}
+
+pub fn seconds(interval: Fraction) -> f64 {
+    interval.numerator as f64 / interval.denominator.max(1) as f64
+}
+
+pub fn to_fraction(seconds: f64) -> Fraction {
+    let numerator = (seconds * INTERVAL_DENOMINATOR as f64).round().max(1.0);
+    Fraction::new(numerator as u32, INTERVAL_DENOMINATOR)
+}
+
+/// frame rate for fast cameras, seconds per frame for long exposures
+pub fn describe(interval: Fraction) -> String {
+    let seconds = seconds(interval);
+    if seconds <= 1.0 {
+        format!("{:.2} fps", 1.0 / seconds)
+    } else {
+        format!("1 frame per {:.1} s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_snap_to_the_steps_within_the_range() {
+        let range = SizeRange {
+            min_width: 16,
+            max_width: 1920,
+            step_width: 16,
+            min_height: 1,
+            max_height: 11,
+            step_height: 4,
+        };
+        assert_eq!(range.clamp(640, 5), (640, 5));
+        assert_eq!(range.clamp(650, 7), (656, 9));
+        assert_eq!(range.clamp(0, 0), (16, 1));
+        // the maximum height is not a valid height, 9 is the largest below it
+        assert_eq!(range.clamp(4000, 11), (1920, 9));
+        assert_eq!(range.clamp(4000, 100), (1920, 9));
+    }
+
+    #[test]
+    fn intervals_snap_to_the_steps_within_the_range() {
+        let range = IntervalRange {
+            min: 0.01,
+            max: 1.0,
+            step: 0.01,
+        };
+        assert!((range.snap(0.123) - 0.12).abs() < 1e-9);
+        assert!((range.snap(0.0) - 0.01).abs() < 1e-9);
+        assert!((range.snap(5.0) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn fractions_keep_the_interval() {
+        for interval in [0.0333, 0.5, 2.0, 12.5] {
+            assert!((seconds(to_fraction(interval)) - interval).abs() < 1e-4);
+        }
+    }
+}
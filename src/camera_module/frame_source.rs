@@ -0,0 +1,50 @@
+use std::{fmt, io::Result};
+
+use super::Image;
+
+/// Anything that can deliver frames to the spectrograph, tracer and calibration.
+pub trait FrameSource: Send {
+    /// Short description of the source shown in the ui
+    fn name(&self) -> String;
+
+    /// Identifies the camera the frames come from, e.g. for its bad pixel mask. Unlike the
+    /// name it has to tell identical models apart.
+    fn key(&self) -> String {
+        self.name()
+    }
+
+    /// Returns the next frame, blocks until one is available
+    fn next_frame(&mut self) -> Result<Image>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SourceKind {
+    #[default]
+    V4l2,
+    ImageFolder,
+    Synthetic,
+    Replay,
+    Pipe,
+}
+
+impl SourceKind {
+    pub const ALL: [SourceKind; 5] = [
+        SourceKind::V4l2,
+        SourceKind::ImageFolder,
+        SourceKind::Synthetic,
+        SourceKind::Replay,
+        SourceKind::Pipe,
+    ];
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceKind::V4l2 => write!(f, "V4L2 camera"),
+            SourceKind::ImageFolder => write!(f, "image folder"),
+            SourceKind::Synthetic => write!(f, "synthetic"),
+            SourceKind::Replay => write!(f, "recorded session"),
+            SourceKind::Pipe => write!(f, "raw pipe"),
+        }
+    }
+}
@@ -1,41 +1,257 @@
-use egui::{self, ColorImage, TextureHandle, Ui};
-use image::{buffer::ConvertBuffer, ImageBuffer, Rgb, RgbaImage};
+use std::{ops::Deref, time::Duration};
+
+use egui::{self, Color32, ColorImage, TextureHandle, Ui};
+use image::{ImageBuffer, Rgb};
 use line_drawing::XiaolinWu;
 
+use super::{CfaPattern, Crop, Exposure, Roi, Sampler};
 use crate::calibration_module::Line;
 
-#[derive(serde::Serialize, serde::Deserialize)]
+/// smallest sum of kernel weights from which an interpolated sample is trusted
+const MIN_SAMPLE_WEIGHT: f32 = 1e-3;
+
+/// Result of reading an image along a [`Line`] or at a single position.
+#[derive(Debug, Clone, Copy)]
+pub struct LineSample {
+    /// None if no usable pixel contributed, e.g. outside of the region of interest
+    pub lightness: Option<f32>,
+    /// mean of the red, green and blue samples relative to full scale, for raw images only
+    /// the photosites of the respective colour contribute
+    pub channels: [Option<f32>; 3],
+    /// a pixel on the line reached the maximum of the sensor
+    pub saturated: bool,
+}
+
+/// Channels of one pixel row of a band, see [`Image::read_band`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BandRow {
+    /// samples relative to full scale, weighted by the covered fraction of their pixel
+    pub sums: [f32; 3],
+    /// total covered fraction of the pixels that contributed to each channel
+    pub weights: [f32; 3],
+    /// sum of the squared covered fractions, for the read noise of each channel
+    pub squared_weights: [f32; 3],
+    /// width of the band in pixels, including bad pixels and other photosites
+    pub area: f32,
+    /// a pixel in the row reached the maximum of the sensor
+    pub saturated: bool,
+}
+
+impl BandRow {
+    /// estimate of the sum of `channel` over the whole row, missing pixels are filled
+    /// with the mean of the others
+    pub fn total(&self, channel: usize) -> f32 {
+        if self.weights[channel] > 0.0 {
+            self.sums[channel] * self.area / self.weights[channel]
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Sequence number and capture time the driver assigned to a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DriverStamp {
+    pub sequence: u32,
+    /// time of capture on the monotonic clock of the driver
+    pub timestamp: Duration,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct Image {
     pub width: usize,
     pub height: usize,
-    pub(crate) data: Vec<u8>,
+    /// rgb samples scaled to the full range of u16 regardless of the sensor bit depth
+    pub(crate) data: Vec<u16>,
+    /// number of significant bits per sample delivered by the sensor
+    pub bit_depth: u8,
+    /// set for raw sensor data, then only the channel of the photosite is non zero
+    #[serde(default)]
+    pub cfa: Option<CfaPattern>,
+    /// exposure of the camera, if it was known and settled when the frame was taken
+    #[serde(default)]
+    pub exposure: Option<Exposure>,
+    /// set if the image was cropped to a region of interest, lines are always given
+    /// relative to the full frame
+    #[serde(default)]
+    pub crop: Option<Crop>,
+    /// only set for frames of V4L2 cameras
+    #[serde(default)]
+    pub stamp: Option<DriverStamp>,
+    /// one entry per pixel, masked pixels are ignored when reading lines
+    #[serde(skip)]
+    pub(crate) bad_pixels: Option<Vec<bool>>,
     #[serde(skip)]
     pub(crate) texture: Option<TextureHandle>,
 }
 
 impl Image {
+    /// `data` has to contain rgb samples with `bit_depth` significant bits each
+    pub fn new(width: usize, height: usize, mut data: Vec<u16>, bit_depth: u8) -> Self {
+        assert_eq!(data.len(), width * height * 3);
+        assert!((1..=16).contains(&bit_depth));
+        if bit_depth < 16 {
+            data.iter_mut()
+                .for_each(|val| *val = scale_to_full_range(*val, bit_depth));
+        }
+        Self {
+            width,
+            height,
+            data,
+            bit_depth,
+            cfa: None,
+            exposure: None,
+            crop: None,
+            stamp: None,
+            bad_pixels: None,
+            texture: None,
+        }
+    }
+
+    /// Cuts out `roi`, given in pixels of the full frame, parts outside of the image are
+    /// ignored. The image is returned unchanged if it does not overlap the roi at all.
+    pub fn cropped(self, roi: Roi) -> Self {
+        let (x0, y0) = self.origin();
+        let (full_width, full_height) = self.full_size();
+        let left = roi.x.max(x0) - x0;
+        let top = roi.y.max(y0) - y0;
+        let right = (roi.x + roi.width).min(x0 + self.width).saturating_sub(x0);
+        let bottom = (roi.y + roi.height)
+            .min(y0 + self.height)
+            .saturating_sub(y0);
+        if left >= right
+            || top >= bottom
+            || (right - left, bottom - top) == (self.width, self.height)
+        {
+            return self;
+        }
+        let width = right - left;
+        let data = (top..bottom)
+            .flat_map(|y| {
+                let row = 3 * y * self.width;
+                self.data[row + 3 * left..row + 3 * right].iter().copied()
+            })
+            .collect();
+        let bad_pixels = self.bad_pixels.as_ref().map(|bad| {
+            (top..bottom)
+                .flat_map(|y| {
+                    bad[y * self.width + left..y * self.width + right]
+                        .iter()
+                        .copied()
+                })
+                .collect()
+        });
+        Self {
+            width,
+            height: bottom - top,
+            data,
+            bit_depth: self.bit_depth,
+            cfa: self.cfa.map(|cfa| cfa.shifted(left, top)),
+            exposure: self.exposure,
+            crop: Some(Crop {
+                x: x0 + left,
+                y: y0 + top,
+                full_width,
+                full_height,
+            }),
+            stamp: self.stamp,
+            bad_pixels,
+            texture: None,
+        }
+    }
+
+    /// top left corner within the full frame
+    pub fn origin(&self) -> (usize, usize) {
+        match self.crop {
+            Some(crop) => (crop.x, crop.y),
+            None => (0, 0),
+        }
+    }
+
+    /// size of the frame before cropping
+    pub fn full_size(&self) -> (usize, usize) {
+        match self.crop {
+            Some(crop) => (crop.full_width, crop.full_height),
+            None => (self.width, self.height),
+        }
+    }
+
+    /// the part of the full frame covered by the image, in the normalised coordinates of [`Line`]
+    pub fn region(&self) -> egui::Rect {
+        let (x0, y0) = self.origin();
+        let (full_width, full_height) = self.full_size();
+        egui::Rect::from_min_max(
+            egui::pos2(
+                x0 as f32 / full_width as f32,
+                y0 as f32 / full_height as f32,
+            ),
+            egui::pos2(
+                (x0 + self.width) as f32 / full_width as f32,
+                (y0 + self.height) as f32 / full_height as f32,
+            ),
+        )
+    }
+
+    /// converts a point of a [`Line`] to pixel coordinates of this image
+    fn to_pixel(&self, point: (f32, f32)) -> (f32, f32) {
+        let (x0, y0) = self.origin();
+        let (full_width, full_height) = self.full_size();
+        (
+            point.0 * full_width as f32 - x0 as f32,
+            point.1 * full_height as f32 - y0 as f32,
+        )
+    }
+
+    /// `mosaic` has to contain one sample per photosite with `bit_depth` significant bits each
+    pub fn new_raw(
+        width: usize,
+        height: usize,
+        mosaic: Vec<u16>,
+        bit_depth: u8,
+        cfa: CfaPattern,
+    ) -> Self {
+        assert_eq!(mosaic.len(), width * height);
+        let mut data = vec![0; width * height * 3];
+        for (i, val) in mosaic.into_iter().enumerate() {
+            data[3 * i + cfa.channel(i % width, i / width)] = val;
+        }
+        let mut img = Self::new(width, height, data, bit_depth);
+        img.cfa = Some(cfa);
+        img
+    }
+
     pub fn get_texture(&mut self, ui: &mut Ui) -> &egui::TextureHandle {
         if self.texture.is_some() {
             return self.texture.as_ref().unwrap();
         }
-        let buf: RgbaImage = ImageBuffer::<Rgb<u8>, &[u8]>::from_raw(
-            self.width as u32,
-            self.height as u32,
-            &self.data,
-        )
-        .expect("building buffer failed")
-        .convert();
-        let image = ColorImage::from_rgba_unmultiplied([self.width, self.height], &buf);
         self.texture = Some(ui.ctx().load_texture(
             "calibration img",
-            image,
+            self.to_color_image(),
             egui::TextureFilter::Linear,
         ));
         self.texture.as_ref().unwrap()
     }
 
-    pub fn get(&self, x: usize, y: usize) -> Option<(u8, u8, u8)> {
-        if self.width < x {
+    pub fn to_color_image(&self) -> ColorImage {
+        let pixels = self
+            .data
+            .chunks_exact(3)
+            .map(|rgb| {
+                Color32::from_rgb(
+                    (rgb[0] >> 8) as u8,
+                    (rgb[1] >> 8) as u8,
+                    (rgb[2] >> 8) as u8,
+                )
+            })
+            .collect();
+        ColorImage {
+            size: [self.width, self.height],
+            pixels,
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<(u16, u16, u16)> {
+        if x >= self.width || y >= self.height {
             return None;
         }
         let index = 3 * (y * self.width + x);
@@ -46,41 +262,344 @@ impl Image {
         ))
     }
 
+    pub fn is_bad(&self, x: usize, y: usize) -> bool {
+        match self.bad_pixels.as_ref() {
+            Some(bad) => x < self.width && bad.get(y * self.width + x).copied().unwrap_or(false),
+            None => false,
+        }
+    }
+
     pub fn aspect_ratio(&self) -> f32 {
         self.width as f32 / self.height as f32
     }
 
-    pub fn read_line_lightness(&self, line: &Line) -> f32 {
-        let start = line.start;
-        let end = line.end;
+    pub fn read_line_lightness(&self, line: &Line) -> Option<f32> {
+        self.read_line(line, Sampler::default()).lightness
+    }
+
+    /// Mean lightness and channels along the line, which is sampled once per pixel of its
+    /// length at exact subpixel positions. Also tells whether any contributing pixel clipped.
+    pub fn read_line(&self, line: &Line, sampler: Sampler) -> LineSample {
+        let (x0, y0) = self.to_pixel(line.start);
+        let (x1, y1) = self.to_pixel(line.end);
+        let count = (x1 - x0).hypot(y1 - y0).ceil().max(1.0) as usize;
 
         let mut total = 0.0;
-        let mut total_weights = 0.0;
-
-        for ((x, y), s) in XiaolinWu::<_, isize>::new(
-            (start.0 * self.width as f32, start.1 * self.height as f32),
-            (end.0 * self.width as f32, end.1 * self.height as f32),
-        ) {
-            if let Some((r, g, b)) = self.get(x as usize, y as usize) {
-                total += rgb_lightness(r, g, b) * s;
-                total_weights += s;
+        let mut total_samples = 0;
+        let mut saturated = false;
+        let mut channels = [0.0; 3];
+        let mut channel_samples = [0; 3];
+
+        for i in 0..count {
+            let t = (i as f32 + 0.5) / count as f32;
+            let sample = self.sample(x0 + t * (x1 - x0), y0 + t * (y1 - y0), sampler);
+            saturated |= sample.saturated;
+            if let Some(val) = sample.lightness {
+                total += val;
+                total_samples += 1;
+            }
+            for channel in 0..3 {
+                if let Some(val) = sample.channels[channel] {
+                    channels[channel] += val;
+                    channel_samples[channel] += 1;
+                }
+            }
+        }
+        let mean = |total: f32, samples: usize| (samples > 0).then(|| total / samples as f32);
+        LineSample {
+            lightness: mean(total, total_samples),
+            channels: [
+                mean(channels[0], channel_samples[0]),
+                mean(channels[1], channel_samples[1]),
+                mean(channels[2], channel_samples[2]),
+            ],
+            saturated,
+        }
+    }
+
+    /// Interpolates the image at `(x, y)` in pixel coordinates. Bad pixels and pixels beyond
+    /// a cropped image are left out and the weights of the others renormalised.
+    fn sample(&self, x: f32, y: f32, sampler: Sampler) -> LineSample {
+        let mut lightness = (0.0, 0.0);
+        let mut channels = [(0.0, 0.0); 3];
+        let mut saturated = false;
+        sampler.for_each_weight(x, y, |px, py, weight| {
+            if px < 0 || py < 0 || self.is_bad(px as usize, py as usize) {
+                return;
             }
+            let (px, py) = (px as usize, py as usize);
+            let (r, g, b) = match self.get(px, py) {
+                Some(rgb) => rgb,
+                None => return,
+            };
+            saturated |= r.max(g).max(b) == u16::MAX;
+            for (channel, val) in [r, g, b].into_iter().enumerate() {
+                let photosite = self
+                    .cfa
+                    .map(|cfa| cfa.channel(px, py) == channel)
+                    .unwrap_or(true);
+                if photosite {
+                    channels[channel].0 += val as f32 / u16::MAX as f32 * weight;
+                    channels[channel].1 += weight;
+                }
+            }
+            if let Some(val) = self.lightness(px, py) {
+                lightness.0 += val * weight;
+                lightness.1 += weight;
+            }
+        });
+        // too little weight left, e.g. only negative lobes of the kernel
+        let mean = |(sum, weight): (f32, f32)| (weight > MIN_SAMPLE_WEIGHT).then(|| sum / weight);
+        LineSample {
+            lightness: mean(lightness),
+            channels: channels.map(mean),
+            saturated,
         }
-        total / total_weights
     }
-}
 
-impl From<ImageBuffer<Rgb<u8>, &[u8]>> for Image {
-    fn from(value: ImageBuffer<Rgb<u8>, &[u8]>) -> Self {
-        Self {
-            width: value.width() as usize,
-            height: value.height() as usize,
-            data: value.to_vec(),
-            texture: None,
+    /// Reads every pixel row of the band between the lines `left` and `right` whose centre lies
+    /// between `top` and `bottom`, all relative to the full frame. Pixels only partly inside the
+    /// band count with the covered fraction, bad pixels are left out.
+    pub fn read_band(&self, left: &Line, right: &Line, top: f32, bottom: f32) -> Vec<BandRow> {
+        let (_, full_height) = self.full_size();
+        let (_, y0) = self.origin();
+        let first = self.to_pixel((0.0, top)).1.round().max(0.0) as usize;
+        let last = (self.to_pixel((0.0, bottom)).1.round().max(0.0) as usize).min(self.height);
+        (first..last)
+            .map(|y| {
+                let center = ((y0 + y) as f32 + 0.5) / full_height as f32;
+                let a = self.to_pixel((left.cut_with_horizontal(center), center)).0;
+                let b = self.to_pixel((right.cut_with_horizontal(center), center)).0;
+                let (a, b) = (a.min(b), a.max(b));
+                let mut row = BandRow {
+                    area: b.min(self.width as f32) - a.max(0.0),
+                    ..BandRow::default()
+                };
+                let columns =
+                    a.floor().max(0.0) as usize..(b.ceil().max(0.0) as usize).min(self.width);
+                for x in columns {
+                    let coverage = b.min(x as f32 + 1.0) - a.max(x as f32);
+                    if coverage <= 0.0 || self.is_bad(x, y) {
+                        continue;
+                    }
+                    let (red, green, blue) = match self.get(x, y) {
+                        Some(rgb) => rgb,
+                        None => continue,
+                    };
+                    row.saturated |= red.max(green).max(blue) == u16::MAX;
+                    for (channel, val) in [red, green, blue].into_iter().enumerate() {
+                        let photosite = self
+                            .cfa
+                            .map(|cfa| cfa.channel(x, y) == channel)
+                            .unwrap_or(true);
+                        if photosite {
+                            row.sums[channel] += val as f32 / u16::MAX as f32 * coverage;
+                            row.weights[channel] += coverage;
+                            row.squared_weights[channel] += coverage * coverage;
+                        }
+                    }
+                }
+                row.area = row.area.max(0.0);
+                row
+            })
+            .collect()
+    }
+
+    /// brightest single sample of any channel on the pixels touched by the line
+    pub fn max_along_line(&self, line: &Line) -> Option<u16> {
+        let start = line.start;
+        let end = line.end;
+        XiaolinWu::<_, isize>::new(self.to_pixel(start), self.to_pixel(end))
+            .filter(|((x, y), _)| *x >= 0 && *y >= 0)
+            .filter(|((x, y), _)| !self.is_bad(*x as usize, *y as usize))
+            .filter_map(|((x, y), _)| self.get(x as usize, y as usize))
+            .map(|(r, g, b)| r.max(g).max(b))
+            .max()
+    }
+
+    /// brightest sample of the whole image, ignoring bad pixels
+    pub fn max_sample(&self) -> Option<u16> {
+        self.data
+            .chunks_exact(3)
+            .enumerate()
+            .filter(|(i, _)| !self.is_bad(i % self.width, i / self.width))
+            .filter_map(|(_, rgb)| rgb.iter().max().copied())
+            .max()
+    }
+
+    /// lightness of a single pixel, for raw images this is the value of the photosite alone
+    pub fn lightness(&self, x: usize, y: usize) -> Option<f32> {
+        let (r, g, b) = self.get(x, y)?;
+        match self.cfa {
+            Some(_) => Some((r as f32 + g as f32 + b as f32) / u16::MAX as f32),
+            None => Some(rgb_lightness(r, g, b)),
         }
     }
 }
 
-pub const fn rgb_lightness(r: u8, g: u8, b: u8) -> f32 {
-    (r as f32 + g as f32 + b as f32) / (255.0 * 3.0)
+impl<C: Deref<Target = [u8]>> From<ImageBuffer<Rgb<u8>, C>> for Image {
+    fn from(value: ImageBuffer<Rgb<u8>, C>) -> Self {
+        Self::new(
+            value.width() as usize,
+            value.height() as usize,
+            value.iter().map(|val| *val as u16).collect(),
+            8,
+        )
+    }
+}
+
+impl<C: Deref<Target = [u16]>> From<ImageBuffer<Rgb<u16>, C>> for Image {
+    fn from(value: ImageBuffer<Rgb<u16>, C>) -> Self {
+        Self::new(
+            value.width() as usize,
+            value.height() as usize,
+            value.to_vec(),
+            16,
+        )
+    }
+}
+
+/// replicates the high bits into the low bits so that the maximum maps to u16::MAX
+pub const fn scale_to_full_range(val: u16, bit_depth: u8) -> u16 {
+    let shift = 16 - bit_depth as u32;
+    let val = val << shift;
+    let mut scaled = val;
+    let mut filled = bit_depth as u32;
+    while filled < 16 {
+        scaled |= val >> filled;
+        filled += bit_depth as u32;
+    }
+    scaled
+}
+
+pub const fn rgb_lightness(r: u16, g: u16, b: u16) -> f32 {
+    (r as f32 + g as f32 + b as f32) / (u16::MAX as f32 * 3.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_module::Roi;
+
+    /// 16 bit grey image whose value rises by `step` per pixel column
+    fn ramp(width: usize, height: usize, step: u16) -> Image {
+        let data = (0..width * height)
+            .flat_map(|i| [(i % width) as u16 * step; 3])
+            .collect();
+        Image::new(width, height, data, 16)
+    }
+
+    fn vertical(x: f32) -> Line {
+        Line {
+            start: (x, 0.0),
+            end: (x, 1.0),
+        }
+    }
+
+    #[test]
+    fn lines_are_sampled_between_pixel_centres() {
+        let img = ramp(16, 8, 1000);
+        // a quarter pixel right of the centre of pixel 5
+        let sample = img.read_line(&vertical(5.75 / 16.0), Sampler::Bilinear);
+        let expected = 5250.0 / u16::MAX as f32;
+        for val in sample.channels {
+            assert!((val.unwrap() - expected).abs() < 1e-5);
+        }
+        assert!((sample.lightness.unwrap() - expected).abs() < 1e-5);
+    }
+
+    #[test]
+    fn raw_channels_only_come_from_their_photosites() {
+        let cfa = CfaPattern::Rggb;
+        let level = [52428, 26214, 13107];
+        let mosaic = (0..8 * 8)
+            .map(|i| level[cfa.channel(i % 8, i / 8)])
+            .collect();
+        let img = Image::new_raw(8, 8, mosaic, 16, cfa);
+        let line = Line {
+            start: (0.1, 0.2),
+            end: (0.9, 0.7),
+        };
+        for sampler in Sampler::ALL {
+            let sample = img.read_line(&line, sampler);
+            for (val, level) in sample.channels.iter().zip(level) {
+                assert!((val.unwrap() - level as f32 / u16::MAX as f32).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn lines_without_usable_pixels_have_no_value() {
+        let img = ramp(16, 8, 1000).cropped(Roi {
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 8,
+        });
+        let outside = img.read_line(&vertical(0.8), Sampler::Bilinear);
+        assert!(outside.lightness.is_none());
+        assert!(outside.channels.iter().all(Option::is_none));
+
+        let mut img = ramp(16, 8, 1000);
+        img.bad_pixels = Some(vec![true; 16 * 8]);
+        let masked = img.read_line(&vertical(0.5), Sampler::Lanczos);
+        assert!(masked.lightness.is_none());
+        assert!(masked.channels.iter().all(Option::is_none));
+        assert!(!masked.saturated);
+    }
+
+    #[test]
+    fn samples_are_scaled_to_the_full_range() {
+        for bit_depth in [8, 10, 12, 16] {
+            let max = (u16::MAX as u32 >> (16 - bit_depth)) as u16;
+            assert_eq!(scale_to_full_range(0, bit_depth), 0);
+            assert_eq!(scale_to_full_range(max, bit_depth), u16::MAX);
+            // every step of the sensor stays a step
+            assert!((1..=max).all(|val| {
+                scale_to_full_range(val, bit_depth) > scale_to_full_range(val - 1, bit_depth)
+            }));
+        }
+        assert_eq!(scale_to_full_range(0xab, 8), 0xabab);
+        assert_eq!(scale_to_full_range(0x200, 10), 0x8020);
+    }
+
+    #[test]
+    fn cropping_keeps_the_position_in_the_full_frame() {
+        let mosaic = (0..8 * 6).map(|i| i as u16).collect();
+        let img = Image::new_raw(8, 6, mosaic, 16, CfaPattern::Rggb);
+        let roi = Roi {
+            x: 3,
+            y: 1,
+            width: 4,
+            height: 3,
+        };
+        let cropped = img.clone().cropped(roi);
+        assert_eq!((cropped.width, cropped.height), (4, 3));
+        assert_eq!(cropped.origin(), (3, 1));
+        assert_eq!(cropped.full_size(), (8, 6));
+        for y in 0..3 {
+            for x in 0..4 {
+                assert_eq!(cropped.get(x, y), img.get(x + 3, y + 1));
+            }
+        }
+        // a second crop is given in the full frame as well
+        let again = cropped.cropped(Roi {
+            x: 4,
+            y: 2,
+            width: 10,
+            height: 10,
+        });
+        assert_eq!((again.width, again.height), (3, 2));
+        assert_eq!(again.origin(), (4, 2));
+        assert_eq!(again.get(0, 0), img.get(4, 2));
+        assert_eq!(again.cfa, Some(CfaPattern::Rggb));
+    }
+
+    #[test]
+    fn get_checks_both_coordinates() {
+        let img = ramp(4, 2, 1);
+        assert!(img.get(3, 1).is_some());
+        assert!(img.get(4, 0).is_none());
+        assert!(img.get(0, 2).is_none());
+    }
 }
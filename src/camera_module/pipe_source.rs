@@ -0,0 +1,331 @@
+use std::{
+    fmt, fs,
+    io::{self, Error, ErrorKind, Read, Result},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+    thread,
+    time::Duration,
+};
+
+use v4l::{Format, FourCC};
+
+use super::{decode, FrameSource, Image};
+
+/// decoded frames waiting for the acquisition thread
+const QUEUE_LENGTH: usize = 2;
+/// longest time `next_frame` blocks, so the source can be closed while no data arrives
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// path with which stdin is selected
+pub const STDIN: &str = "-";
+
+pub const USAGE: &str = "usage: speckmeter [--pipe <path or - for stdin> --width <pixels> \
+--height <pixels> [--format rgb|grey|y16]]";
+
+/// Layout of the raw frames written to the pipe, without any header or padding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PipeFormat {
+    /// 8 bit per channel, e.g. `ffmpeg -pix_fmt rgb24`
+    #[default]
+    Rgb,
+    /// 8 bit luminance, e.g. `ffmpeg -pix_fmt gray`
+    Grey,
+    /// 16 bit little endian luminance, e.g. `ffmpeg -pix_fmt gray16le`
+    Y16,
+}
+
+impl PipeFormat {
+    pub const ALL: [PipeFormat; 3] = [PipeFormat::Rgb, PipeFormat::Grey, PipeFormat::Y16];
+
+    fn fourcc(&self) -> FourCC {
+        match self {
+            PipeFormat::Rgb => FourCC::new(b"RGB3"),
+            PipeFormat::Grey => FourCC::new(b"GREY"),
+            PipeFormat::Y16 => FourCC::new(b"Y16 "),
+        }
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PipeFormat::Rgb => 3,
+            PipeFormat::Grey => 1,
+            PipeFormat::Y16 => 2,
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "rgb" | "rgb24" | "rgb3" => Some(PipeFormat::Rgb),
+            "grey" | "gray" => Some(PipeFormat::Grey),
+            "y16" | "gray16le" | "grey16" => Some(PipeFormat::Y16),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PipeFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipeFormat::Rgb => write!(f, "rgb"),
+            PipeFormat::Grey => write!(f, "grey"),
+            PipeFormat::Y16 => write!(f, "y16"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipeSettings {
+    /// named pipe or file to read from, [`STDIN`] for stdin
+    pub path: String,
+    pub width: u32,
+    pub height: u32,
+    pub format: PipeFormat,
+}
+
+impl Default for PipeSettings {
+    fn default() -> Self {
+        Self {
+            path: STDIN.to_string(),
+            width: 640,
+            height: 480,
+            format: PipeFormat::default(),
+        }
+    }
+}
+
+impl PipeSettings {
+    /// Reads the settings from the command line arguments without the program name,
+    /// returns `None` if no pipe was requested.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Option<Self>> {
+        let mut path = None;
+        let mut width = None;
+        let mut height = None;
+        let mut format = PipeFormat::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next().ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidInput, format!("{} needs a value", arg))
+                })
+            };
+            match arg.as_str() {
+                "--pipe" => path = Some(value()?),
+                "--width" => width = Some(parse_size(&value()?)?),
+                "--height" => height = Some(parse_size(&value()?)?),
+                "--format" => {
+                    let name = value()?;
+                    format = PipeFormat::parse(&name).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("unknown pixel format {}", name),
+                        )
+                    })?
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("unknown argument {}", arg),
+                    ))
+                }
+            }
+        }
+        let path = match path {
+            Some(path) => path,
+            None if width.is_none() && height.is_none() => return Ok(None),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "--width and --height are only used with --pipe",
+                ))
+            }
+        };
+        match (width, height) {
+            (Some(width), Some(height)) => Ok(Some(Self {
+                path,
+                width,
+                height,
+                format,
+            })),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--pipe needs --width and --height",
+            )),
+        }
+    }
+
+    fn describe(&self) -> String {
+        let input = match self.path.as_str() {
+            STDIN => "stdin".to_string(),
+            path => path.to_string(),
+        };
+        format!("{} {}x{} {}", input, self.width, self.height, self.format)
+    }
+}
+
+fn parse_size(value: &str) -> Result<u32> {
+    match value.parse() {
+        Ok(size) if size > 0 => Ok(size),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a valid size in pixels", value),
+        )),
+    }
+}
+
+/// Reads raw frames of a fixed size from stdin or a named pipe, e.g. written by `ffmpeg`,
+/// `libcamera-vid` or a simulation. Ends when the writer closes the pipe.
+pub struct PipeSource {
+    settings: PipeSettings,
+    receiver: Receiver<Result<Image>>,
+}
+
+impl PipeSource {
+    /// The input is opened and read on its own thread, as opening a named pipe blocks until
+    /// there is a writer. That thread ends with the pipe or with the first frame after the
+    /// source was dropped.
+    pub fn new(settings: PipeSettings) -> Result<Self> {
+        if settings.width == 0 || settings.height == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the frame size must not be zero",
+            ));
+        }
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        {
+            let settings = settings.clone();
+            thread::spawn(move || read_frames(settings, sender));
+        }
+        Ok(Self { settings, receiver })
+    }
+}
+
+impl FrameSource for PipeSource {
+    fn name(&self) -> String {
+        format!("raw frames from {}", self.settings.describe())
+    }
+
+    fn next_frame(&mut self) -> Result<Image> {
+        match self.receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(frame) => frame,
+            Err(RecvTimeoutError::Timeout) => Err(Error::new(
+                ErrorKind::TimedOut,
+                "no frame was written to the pipe",
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("{} was closed", self.settings.describe()),
+            )),
+        }
+    }
+}
+
+fn read_frames(settings: PipeSettings, sender: SyncSender<Result<Image>>) {
+    let mut input: Box<dyn Read> = match settings.path.as_str() {
+        STDIN => Box::new(io::stdin()),
+        path => match fs::File::open(path) {
+            Ok(file) => Box::new(file),
+            Err(err) => {
+                let _ = sender.send(Err(err));
+                return;
+            }
+        },
+    };
+    let format = Format::new(settings.width, settings.height, settings.format.fourcc());
+    let mut buf =
+        vec![
+            0;
+            settings.width as usize * settings.height as usize * settings.format.bytes_per_pixel()
+        ];
+    loop {
+        if let Err(err) = input.read_exact(&mut buf) {
+            // the writer closing the pipe ends the source, even within a frame
+            if err.kind() != ErrorKind::UnexpectedEof {
+                let _ = sender.send(Err(err));
+            }
+            return;
+        }
+        if sender.send(decode::decode(&buf, &format)).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Option<PipeSettings>> {
+        PipeSettings::from_args(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn pipe_is_only_opened_when_requested() {
+        assert_eq!(parse("").unwrap(), None);
+    }
+
+    #[test]
+    fn arguments_are_parsed_in_any_order() {
+        let settings = parse("--height 480 --format y16 --pipe - --width 640")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            settings,
+            PipeSettings {
+                path: STDIN.to_string(),
+                width: 640,
+                height: 480,
+                format: PipeFormat::Y16,
+            }
+        );
+        let settings = parse("--pipe /tmp/frames --width 8 --height 2")
+            .unwrap()
+            .unwrap();
+        assert_eq!(settings.format, PipeFormat::Rgb);
+        assert_eq!(settings.path, "/tmp/frames");
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        for args in [
+            "--pipe -",
+            "--pipe - --width 640",
+            "--width 640 --height 480",
+            "--pipe - --width 0 --height 480",
+            "--pipe - --width 640 --height many",
+            "--pipe - --width 640 --height 480 --format yuv",
+            "--pipe - --width 640 --height 480 --verbose",
+            "--pipe - --width 640 --height",
+        ] {
+            let err = parse(args).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", args);
+        }
+    }
+
+    /// waits for the reader thread however long it takes
+    fn next_frame(source: &mut PipeSource) -> Result<Image> {
+        loop {
+            match source.next_frame() {
+                Err(err) if err.kind() == ErrorKind::TimedOut => continue,
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn frames_are_read_until_the_pipe_closes() {
+        let path = std::env::temp_dir().join(format!("speckmeter_pipe_{}", std::process::id()));
+        // two grey frames and the start of a third
+        fs::write(&path, [10u8, 20, 30, 40, 50, 60, 70, 80, 90]).unwrap();
+        let mut source = PipeSource::new(PipeSettings {
+            path: path.to_string_lossy().into_owned(),
+            width: 2,
+            height: 2,
+            format: PipeFormat::Grey,
+        })
+        .unwrap();
+        let first = next_frame(&mut source).unwrap();
+        assert_eq!(first.get(1, 1).map(|(r, _, _)| r), Some(40 * 257));
+        assert!(next_frame(&mut source).is_ok());
+        let end = next_frame(&mut source).err().map(|err| err.kind());
+        assert_eq!(end, Some(ErrorKind::UnexpectedEof));
+        fs::remove_file(path).unwrap();
+    }
+}
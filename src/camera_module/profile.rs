@@ -0,0 +1,108 @@
+use log::warn;
+use v4l::{
+    control, prelude::*, video::capture::Parameters, video::Capture, Control, Format, FourCC,
+    Fraction,
+};
+
+use super::Roi;
+
+/// Value of a control that can be written back to the camera.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ControlValue {
+    Integer(i64),
+    Boolean(bool),
+}
+
+impl ControlValue {
+    fn from_value(value: &control::Value) -> Option<Self> {
+        match value {
+            control::Value::Integer(val) => Some(ControlValue::Integer(*val)),
+            control::Value::Boolean(val) => Some(ControlValue::Boolean(*val)),
+            _ => None,
+        }
+    }
+
+    fn to_value(self) -> control::Value {
+        match self {
+            ControlValue::Integer(val) => control::Value::Integer(val),
+            ControlValue::Boolean(val) => control::Value::Boolean(val),
+        }
+    }
+}
+
+/// Everything that has to be set up again to get the same frames out of a camera,
+/// stored per device so the calibration stays valid across restarts.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CameraProfile {
+    pub fourcc: [u8; 4],
+    pub width: u32,
+    pub height: u32,
+    /// frame interval as (numerator, denominator)
+    pub interval: (u32, u32),
+    pub demosaic: bool,
+    /// control ids and values in the order the driver reports them
+    pub controls: Vec<(u32, ControlValue)>,
+    #[serde(default)]
+    pub roi: Option<Roi>,
+}
+
+impl CameraProfile {
+    pub fn new(
+        format: &Format,
+        interval: Fraction,
+        demosaic: bool,
+        controls: &[(control::Description, Control)],
+        roi: Option<Roi>,
+    ) -> Self {
+        let writable = |description: &control::Description| {
+            !description.flags.intersects(
+                control::Flags::READ_ONLY | control::Flags::DISABLED | control::Flags::INACTIVE,
+            )
+        };
+        Self {
+            fourcc: format.fourcc.repr,
+            width: format.width,
+            height: format.height,
+            interval: (interval.numerator, interval.denominator),
+            demosaic,
+            controls: controls
+                .iter()
+                .filter(|(description, _)| writable(description))
+                .filter_map(|(description, control)| {
+                    Some((description.id, ControlValue::from_value(&control.value)?))
+                })
+                .collect(),
+            roi,
+        }
+    }
+
+    /// Sets format, frame interval and controls, failures are logged and skipped so a
+    /// partially matching camera still gets as close as possible.
+    pub fn apply(&self, camera: &Device) -> std::io::Result<Format> {
+        let format = camera.set_format(&Format::new(
+            self.width,
+            self.height,
+            FourCC::new(&self.fourcc),
+        ))?;
+        let interval = Fraction::new(self.interval.0, self.interval.1);
+        if let Err(err) = camera.set_params(&Parameters::new(interval)) {
+            warn!("could not restore frame interval: {}", err)
+        }
+        for (id, value) in self.controls.iter() {
+            let control = Control {
+                id: *id,
+                value: value.to_value(),
+            };
+            if let Err(err) = camera.set_control(control) {
+                warn!("could not restore control {}: {}", id, err)
+            }
+        }
+        Ok(format)
+    }
+}
+
+/// key of a camera in the stored profiles, the bus distinguishes identical models
+pub fn profile_key(camera: &Device) -> std::io::Result<String> {
+    let caps = camera.query_caps()?;
+    Ok(format!("{} ({})", caps.card, caps.bus))
+}
@@ -0,0 +1,166 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use image::{ImageBuffer, Rgb};
+use log::{error, info, warn};
+
+use super::{CameraProfile, CfaPattern, Crop, DriverStamp, Exposure, Image};
+
+/// frames waiting to be written before further frames are skipped
+const QUEUE_LENGTH: usize = 32;
+pub const SESSION_FILE: &str = "session.ron";
+
+/// Description of a recorded session, stored next to its frames.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Session {
+    /// name of the source the frames were acquired from
+    pub source: String,
+    /// local time at which the recording started
+    pub started: String,
+    /// settings of the camera, if it was a V4L2 camera
+    pub profile: Option<CameraProfile>,
+}
+
+/// Everything about a recorded frame apart from its pixels, which are stored losslessly as a
+/// 16 bit png with the same name.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FrameInfo {
+    /// time since the start of the recording at which the frame arrived
+    pub received: Duration,
+    pub bit_depth: u8,
+    pub cfa: Option<CfaPattern>,
+    pub exposure: Option<Exposure>,
+    pub crop: Option<Crop>,
+    pub stamp: Option<DriverStamp>,
+}
+
+/// Writes every frame it is given to a session directory on a background thread, so slow
+/// disks do not hold up the acquisition.
+pub struct Recorder {
+    dir: PathBuf,
+    start: Instant,
+    sender: Option<SyncSender<(FrameInfo, Image)>>,
+    handle: Option<JoinHandle<()>>,
+    written: Arc<AtomicUsize>,
+    skipped: usize,
+}
+
+impl Recorder {
+    /// creates `dir` and writes the session description into it
+    pub fn new(dir: PathBuf, session: &Session) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let description = ron::ser::to_string_pretty(session, ron::ser::PrettyConfig::default())
+            .map_err(|err| Error::new(ErrorKind::Other, err))?;
+        fs::write(dir.join(SESSION_FILE), description)?;
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        let written = Arc::new(AtomicUsize::new(0));
+        let handle = {
+            let dir = dir.clone();
+            let written = written.clone();
+            thread::spawn(move || write_frames(dir, receiver, written))
+        };
+        info!("recording to {}", dir.display());
+        Ok(Self {
+            dir,
+            start: Instant::now(),
+            sender: Some(sender),
+            handle: Some(handle),
+            written,
+            skipped: 0,
+        })
+    }
+
+    /// queues the frame for writing, frames are skipped if the disk cannot keep up
+    pub fn record(&mut self, img: &Image) {
+        let info = FrameInfo {
+            received: self.start.elapsed(),
+            bit_depth: img.bit_depth,
+            cfa: img.cfa,
+            exposure: img.exposure,
+            crop: img.crop,
+            stamp: img.stamp,
+        };
+        let sender = match self.sender.as_ref() {
+            Some(sender) => sender,
+            None => return,
+        };
+        match sender.try_send((info, img.clone())) {
+            Ok(_) => (),
+            Err(TrySendError::Full(_)) => {
+                self.skipped += 1;
+                warn!("the disk cannot keep up, skipped a frame of the recording");
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!("the recording stopped unexpectedly");
+                self.sender = None;
+            }
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn written(&self) -> usize {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+impl Drop for Recorder {
+    /// writes the remaining frames before returning
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("the recording thread panicked")
+            }
+        }
+        info!(
+            "recorded {} frames to {}",
+            self.written(),
+            self.dir.display()
+        );
+    }
+}
+
+/// file name of a frame without extension
+pub fn frame_name(index: usize) -> String {
+    format!("{:06}", index)
+}
+
+fn write_frames(dir: PathBuf, receiver: Receiver<(FrameInfo, Image)>, written: Arc<AtomicUsize>) {
+    for (index, (info, img)) in receiver.into_iter().enumerate() {
+        let path = dir.join(frame_name(index));
+        if let Err(err) = write_frame(&path, &info, &img) {
+            error!("could not write {}: {}", path.display(), err);
+            continue;
+        }
+        written.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn write_frame(path: &Path, info: &FrameInfo, img: &Image) -> Result<()> {
+    let pixels: ImageBuffer<Rgb<u16>, _> =
+        ImageBuffer::from_raw(img.width as u32, img.height as u32, img.data.clone())
+            .expect("the image data should match its size");
+    pixels
+        .save(path.with_extension("png"))
+        .map_err(|err| Error::new(ErrorKind::Other, err))?;
+    let description = ron::to_string(info).map_err(|err| Error::new(ErrorKind::Other, err))?;
+    fs::write(path.with_extension("ron"), description)
+}
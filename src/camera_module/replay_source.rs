@@ -0,0 +1,215 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{
+    recorder::{self, FrameInfo, Session},
+    FrameSource, Image,
+};
+
+/// longest time `next_frame` blocks, so the source can be closed while a frame is not due yet
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Plays back a recorded session with the timing of the original frames, optionally sped up,
+/// and ends after the last frame.
+pub struct ReplaySource {
+    dir: PathBuf,
+    session: Session,
+    /// paths of the frames without extension, in recording order
+    frames: Vec<PathBuf>,
+    next: usize,
+    speed: f32,
+    /// info of the previously returned frame and when it was returned
+    previous: Option<(FrameInfo, Instant)>,
+    /// frame that was read but is not due yet
+    pending: Option<(FrameInfo, Image)>,
+}
+
+impl ReplaySource {
+    pub fn new(dir: impl AsRef<Path>, speed: f32) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let session = read_ron(&dir.join(recorder::SESSION_FILE))?;
+        let mut frames = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("png") {
+                frames.push(path.with_extension(""))
+            }
+        }
+        if frames.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "the session does not contain any frames",
+            ));
+        }
+        frames.sort();
+        Ok(Self {
+            dir,
+            session,
+            frames,
+            next: 0,
+            speed: speed.max(f32::EPSILON),
+            previous: None,
+            pending: None,
+        })
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// time between two frames when they were recorded, from the driver time stamps if known
+    fn original_interval(previous: &FrameInfo, next: &FrameInfo) -> Duration {
+        match (previous.stamp, next.stamp) {
+            (Some(before), Some(after)) if after.timestamp >= before.timestamp => {
+                after.timestamp - before.timestamp
+            }
+            _ => next.received.saturating_sub(previous.received),
+        }
+    }
+
+    fn read_next(&mut self) -> Result<(FrameInfo, Image)> {
+        let path = match self.frames.get(self.next) {
+            Some(path) => path,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("replay of {} finished", self.dir.display()),
+                ))
+            }
+        };
+        self.next += 1;
+        let info: FrameInfo = read_ron(&path.with_extension("ron"))?;
+        let mut img: Image = image::open(path.with_extension("png"))
+            .map_err(|err| Error::new(ErrorKind::Other, err))?
+            .into_rgb16()
+            .into();
+        // the samples were stored in full range already
+        img.bit_depth = info.bit_depth;
+        img.cfa = info.cfa;
+        img.exposure = info.exposure;
+        img.crop = info.crop;
+        img.stamp = info.stamp;
+        Ok((info, img))
+    }
+}
+
+impl FrameSource for ReplaySource {
+    fn name(&self) -> String {
+        format!(
+            "replay of {} started {} ({} frames)",
+            self.session.source,
+            self.session.started,
+            self.frames.len()
+        )
+    }
+
+    fn next_frame(&mut self) -> Result<Image> {
+        if self.pending.is_none() {
+            self.pending = Some(self.read_next()?);
+        }
+        let (info, _) = self.pending.as_ref().expect("the frame was read above");
+        if let Some((previous, returned)) = self.previous.as_ref() {
+            let due = *returned + Self::original_interval(previous, info).div_f32(self.speed);
+            let now = Instant::now();
+            if now < due {
+                thread::sleep((due - now).min(POLL_INTERVAL));
+                if Instant::now() < due {
+                    return Err(Error::new(
+                        ErrorKind::TimedOut,
+                        "the next recorded frame is not due yet",
+                    ));
+                }
+            }
+        }
+        let (info, img) = self.pending.take().expect("the frame was read above");
+        self.previous = Some((info, Instant::now()));
+        Ok(img)
+    }
+}
+
+fn read_ron<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let text = fs::read_to_string(path)?;
+    ron::from_str(&text).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("could not read {}: {}", path.display(), err),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_module::{
+        recorder::Recorder,
+        synthetic_source::tests::{frame, settings},
+        CfaPattern, DriverStamp, Exposure, SyntheticSettings,
+    };
+
+    /// small raw frames of the synthetic spectrum, the second one arrives after a long gap
+    fn frames() -> Vec<Image> {
+        let img = frame(&SyntheticSettings {
+            width: 24,
+            height: 16,
+            ..settings()
+        });
+        let mosaic: Vec<u16> = img.data.iter().step_by(3).map(|val| val >> 4).collect();
+        [0, 600, 610]
+            .iter()
+            .enumerate()
+            .map(|(i, millis)| {
+                let mut raw = Image::new_raw(24, 16, mosaic.clone(), 12, CfaPattern::Grbg);
+                raw.exposure = Some(Exposure {
+                    time: 100 * (i as i64 + 1),
+                    gain: Some(2),
+                });
+                raw.stamp = Some(DriverStamp {
+                    sequence: i as u32 + 7,
+                    timestamp: Duration::from_millis(*millis),
+                });
+                raw
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recorded_frames_are_replayed_unchanged() {
+        let dir = std::env::temp_dir().join(format!("speckmeter_replay_{}", std::process::id()));
+        let recorded = frames();
+        let session = Session {
+            source: "synthetic".to_string(),
+            started: "today".to_string(),
+            profile: None,
+        };
+        let mut recorder = Recorder::new(dir.clone(), &session).unwrap();
+        recorded.iter().for_each(|img| recorder.record(img));
+        drop(recorder);
+
+        let mut source = ReplaySource::new(&dir, 1.0).unwrap();
+        let mut timeouts = 0;
+        for expected in &recorded {
+            let img = loop {
+                match source.next_frame() {
+                    Err(err) if err.kind() == ErrorKind::TimedOut => timeouts += 1,
+                    result => break result.unwrap(),
+                }
+            };
+            assert_eq!((img.width, img.height), (expected.width, expected.height));
+            assert_eq!(img.data, expected.data);
+            assert_eq!(img.bit_depth, 12);
+            assert_eq!(img.cfa, Some(CfaPattern::Grbg));
+            assert_eq!(img.exposure, expected.exposure);
+            assert_eq!(img.stamp, expected.stamp);
+        }
+        // the gap of 600 ms is waited for in polls
+        assert!(timeouts >= 2, "{}", timeouts);
+        let end = source.next_frame().err().map(|err| err.kind());
+        assert_eq!(end, Some(ErrorKind::UnexpectedEof));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+use std::{fmt, io::Result, mem, os::raw::c_void};
+
+use log::{info, warn};
+use v4l::{
+    buffer,
+    prelude::*,
+    v4l2,
+    v4l_sys::{v4l2_crop, v4l2_cropcap, v4l2_rect},
+    video::Capture,
+    Format,
+};
+
+/// Rectangle of the sensor in pixels of the full frame, frames are cropped to it right
+/// after acquisition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Roi {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Roi {
+    pub fn full(width: usize, height: usize) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// the part of the roi that lies within a frame of this size, None if nothing is left
+    pub fn clamp(&self, width: usize, height: usize) -> Option<Self> {
+        let x = self.x.min(width);
+        let y = self.y.min(height);
+        let roi = Self {
+            x,
+            y,
+            width: self.width.min(width - x),
+            height: self.height.min(height - y),
+        };
+        (roi.width > 0 && roi.height > 0).then_some(roi)
+    }
+}
+
+impl fmt::Display for Roi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} at ({}, {})",
+            self.width, self.height, self.x, self.y
+        )
+    }
+}
+
+/// Where a cropped image lies within the full frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Crop {
+    pub x: usize,
+    pub y: usize,
+    pub full_width: usize,
+    pub full_height: usize,
+}
+
+/// Lets the driver crop the sensor to `roi`, or removes the crop if `roi` is None, `full` is
+/// the format of the uncropped frames. Returns true if the driver delivers exactly the roi,
+/// drivers without cropping or that would scale the crop are left uncropped.
+pub fn hardware_crop(camera: &Device, roi: Option<Roi>, full: &Format) -> bool {
+    let fd = camera.handle().fd();
+    let mut cropcap = v4l2_cropcap {
+        type_: buffer::Type::VideoCapture as u32,
+        ..unsafe { mem::zeroed() }
+    };
+    // SAFETY: the driver fills in the struct passed to it, which outlives the call
+    let supported = unsafe {
+        v4l2::ioctl(
+            fd,
+            v4l2::vidioc::VIDIOC_CROPCAP,
+            &mut cropcap as *mut _ as *mut c_void,
+        )
+    };
+    let defrect = cropcap.defrect;
+    // without scaling the default crop rectangle is the full frame
+    if supported.is_err() || defrect.width != full.width || defrect.height != full.height {
+        return false;
+    }
+
+    let rect = match roi {
+        Some(roi) => v4l2_rect {
+            left: defrect.left + roi.x as i32,
+            top: defrect.top + roi.y as i32,
+            width: roi.width as u32,
+            height: roi.height as u32,
+        },
+        None => defrect,
+    };
+    if let Err(err) = set_crop(fd, rect) {
+        warn!("the camera cannot crop to {:?}: {}", roi, err);
+        return false;
+    }
+    let roi = match roi {
+        Some(roi) => roi,
+        None => {
+            if let Err(err) = camera.set_format(full) {
+                warn!("could not restore the full frame size: {}", err)
+            }
+            return false;
+        }
+    };
+    let cropped = match camera.format() {
+        Ok(format) => format.width as usize == roi.width && format.height as usize == roi.height,
+        Err(_) => false,
+    };
+    if cropped {
+        info!("the camera crops to {}", roi);
+        return true;
+    }
+    // the driver scaled the crop back up or changed it, do it in software instead
+    if let Err(err) = set_crop(fd, defrect).and_then(|_| camera.set_format(full).map(|_| ())) {
+        warn!("could not undo the crop of the camera: {}", err)
+    }
+    false
+}
+
+fn set_crop(fd: std::os::raw::c_int, rect: v4l2_rect) -> Result<()> {
+    let mut crop = v4l2_crop {
+        type_: buffer::Type::VideoCapture as u32,
+        c: rect,
+    };
+    // SAFETY: the struct outlives the call
+    unsafe {
+        v4l2::ioctl(
+            fd,
+            v4l2::vidioc::VIDIOC_S_CROP,
+            &mut crop as *mut _ as *mut c_void,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roi_is_clamped_to_the_frame() {
+        let roi = Roi {
+            x: 100,
+            y: 50,
+            width: 200,
+            height: 100,
+        };
+        assert_eq!(roi.clamp(640, 480), Some(roi));
+        assert_eq!(
+            roi.clamp(250, 120),
+            Some(Roi {
+                x: 100,
+                y: 50,
+                width: 150,
+                height: 70,
+            })
+        );
+        assert_eq!(roi.clamp(100, 480), None);
+        assert_eq!(roi.clamp(640, 50), None);
+        assert_eq!(
+            Roi::full(640, 480).clamp(320, 240),
+            Some(Roi::full(320, 240))
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::{f32::consts::PI, fmt};
+
+/// Interpolation with which an image is evaluated between the centres of its pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum Sampler {
+    /// the four nearest pixels
+    #[default]
+    Bilinear,
+    /// cubic convolution over 4x4 pixels, sharper than bilinear
+    Bicubic,
+    /// windowed sinc over 6x6 pixels, the sharpest but may ring at hard edges
+    Lanczos,
+}
+
+/// pixels along one axis of the largest kernel
+const MAX_TAPS: usize = 6;
+
+impl Sampler {
+    pub const ALL: [Sampler; 3] = [Sampler::Bilinear, Sampler::Bicubic, Sampler::Lanczos];
+
+    /// pixels on either side of the sampled position that contribute
+    const fn radius(&self) -> isize {
+        match self {
+            Sampler::Bilinear => 1,
+            Sampler::Bicubic => 2,
+            Sampler::Lanczos => 3,
+        }
+    }
+
+    /// weight of a pixel whose centre is `d` pixels away along one axis
+    fn weight(&self, d: f32) -> f32 {
+        let d = d.abs();
+        match self {
+            Sampler::Bilinear => (1.0 - d).max(0.0),
+            Sampler::Bicubic => {
+                // Keys' kernel with a = -0.5, which reproduces quadratic functions
+                const A: f32 = -0.5;
+                if d < 1.0 {
+                    (A + 2.0) * d.powi(3) - (A + 3.0) * d.powi(2) + 1.0
+                } else if d < 2.0 {
+                    A * d.powi(3) - 5.0 * A * d.powi(2) + 8.0 * A * d - 4.0 * A
+                } else {
+                    0.0
+                }
+            }
+            Sampler::Lanczos => {
+                let radius = self.radius() as f32;
+                if d < f32::EPSILON {
+                    1.0
+                } else if d < radius {
+                    radius * (PI * d).sin() * (PI * d / radius).sin() / (PI * PI * d * d)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    /// Calls `f` with every pixel around `(x, y)` and its weight, the weights sum up to one.
+    /// The position is given in pixel coordinates where pixel `i` covers `i..i + 1`.
+    pub fn for_each_weight(&self, x: f32, y: f32, mut f: impl FnMut(isize, isize, f32)) {
+        // relative to the pixel centres
+        let columns = self.taps(x - 0.5);
+        let rows = self.taps(y - 0.5);
+        let count = 2 * self.radius() as usize;
+        for (py, wy) in &rows[..count] {
+            for (px, wx) in &columns[..count] {
+                let w = wy * wx;
+                if w != 0.0 {
+                    f(*px, *py, w)
+                }
+            }
+        }
+    }
+
+    /// pixels along one axis around `pos` with their normalised weights, only the first
+    /// `2 * radius` entries are used
+    fn taps(&self, pos: f32) -> [(isize, f32); MAX_TAPS] {
+        let base = pos.floor() as isize;
+        let mut taps = [(0, 0.0); MAX_TAPS];
+        let mut sum = 0.0;
+        for (tap, offset) in taps.iter_mut().zip(1 - self.radius()..=self.radius()) {
+            let pixel = base + offset;
+            let weight = self.weight(pos - pixel as f32);
+            *tap = (pixel, weight);
+            sum += weight;
+        }
+        // the lanczos kernel only roughly sums up to one
+        taps.iter_mut().for_each(|(_, weight)| *weight /= sum);
+        taps
+    }
+}
+
+impl fmt::Display for Sampler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sampler::Bilinear => write!(f, "bilinear"),
+            Sampler::Bicubic => write!(f, "bicubic"),
+            Sampler::Lanczos => write!(f, "lanczos"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POSITIONS: [(f32, f32); 5] =
+        [(3.5, 3.5), (3.0, 4.0), (3.75, 2.6), (4.1, 5.49), (2.5, 7.9)];
+
+    /// interpolates `image` at the position
+    fn sample(sampler: Sampler, (x, y): (f32, f32), image: impl Fn(isize, isize) -> f32) -> f32 {
+        let mut val = 0.0;
+        sampler.for_each_weight(x, y, |px, py, weight| val += weight * image(px, py));
+        val
+    }
+
+    #[test]
+    fn kernels_sum_up_to_one() {
+        for sampler in Sampler::ALL {
+            for pos in POSITIONS {
+                assert!((sample(sampler, pos, |_, _| 1.0) - 1.0).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_centres_are_reproduced() {
+        for sampler in Sampler::ALL {
+            let image = |x: isize, y: isize| (x * 7 - y * 3) as f32;
+            let val = sample(sampler, (4.5, 2.5), image);
+            assert!((val - image(4, 2)).abs() < 1e-5, "{}", sampler);
+        }
+    }
+
+    #[test]
+    fn gradients_are_interpolated_linearly() {
+        for sampler in [Sampler::Bilinear, Sampler::Bicubic] {
+            for (x, y) in POSITIONS {
+                // pixel centres lie half a pixel into the pixels
+                let val = sample(sampler, (x, y), |px, py| 2.0 * px as f32 - py as f32);
+                let expected = 2.0 * (x - 0.5) - (y - 0.5);
+                assert!((val - expected).abs() < 1e-4, "{}", sampler);
+            }
+        }
+    }
+
+    #[test]
+    fn kernels_reach_their_radius() {
+        for sampler in Sampler::ALL {
+            let mut pixels = 0;
+            sampler.for_each_weight(3.75, 3.75, |_, _, _| pixels += 1);
+            let taps = 2 * sampler.radius() as usize;
+            assert_eq!(pixels, taps * taps);
+        }
+    }
+}
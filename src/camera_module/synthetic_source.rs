@@ -0,0 +1,454 @@
+use std::{
+    f32::consts::PI,
+    io::{Error, ErrorKind, Result},
+    time::{Duration, Instant},
+};
+
+use egui::{DragValue, Slider, Ui};
+
+use super::{FrameSource, Image};
+use crate::{calibration_module::SpectralLines, LARGEST_WAVELENGTH, SMALLEST_WAVELENGTH};
+
+/// step in nm with which continuous spectra are rendered
+const RENDER_STEP: f32 = 0.25;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyntheticSpectrum {
+    /// (wavelength in nm, intensity) pairs
+    Emission(Vec<(f32, f32)>),
+    /// black body spectrum normalised to its maximum in the visible range
+    Continuous { temperature: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticSettings {
+    pub width: u32,
+    pub height: u32,
+    pub fps: f32,
+    pub grating_const: f32,
+    pub angle: f32,
+    pub distance_to_sensor: f32,
+    pub sensor_width: f32,
+    pub offset: f32,
+    pub tilt: f32,
+    pub spectrum: SyntheticSpectrum,
+    /// standard deviation of the line profile in pixels
+    pub line_width: f32,
+    /// standard deviation of the added noise relative to full scale
+    pub noise: f32,
+    pub gamma: f32,
+    /// intensity which is mapped to full scale, everything above is clipped
+    pub saturation: f32,
+    pub bit_depth: u8,
+    pub colored: bool,
+}
+
+impl Default for SyntheticSettings {
+    fn default() -> Self {
+        Self {
+            width: 640,
+            height: 480,
+            fps: 30.0,
+            grating_const: 500.0,
+            angle: 17.5,
+            distance_to_sensor: 1.0,
+            sensor_width: 0.5,
+            offset: 0.5,
+            tilt: 0.05,
+            spectrum: SyntheticSpectrum::Emission(vec![(405.0, 1.0), (532.0, 0.8), (650.0, 0.6)]),
+            line_width: 2.0,
+            noise: 0.01,
+            gamma: 1.0,
+            saturation: 1.0,
+            bit_depth: 8,
+            colored: false,
+        }
+    }
+}
+
+impl SyntheticSettings {
+    pub fn spectral_lines(&self) -> SpectralLines {
+        SpectralLines::from_geometry(
+            self.grating_const,
+            self.angle,
+            self.distance_to_sensor,
+            self.sensor_width,
+            self.offset,
+            self.tilt,
+        )
+    }
+
+    /// returns true if any setting was changed
+    pub fn ui(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = false;
+        ui.strong("Geometry");
+        ui.label("Angle in degrees");
+        changed |= ui.add(Slider::new(&mut self.angle, -90.0..=90.0)).changed();
+        ui.label("Distance to sensor in mm");
+        changed |= ui
+            .add(Slider::new(&mut self.distance_to_sensor, 0.0..=100.0))
+            .changed();
+        ui.label("Sensor width in mm");
+        changed |= ui
+            .add(Slider::new(&mut self.sensor_width, 0.01..=10.0))
+            .changed();
+        ui.label("Grating constant in lines per mm");
+        changed |= ui
+            .add(Slider::new(&mut self.grating_const, 0.0..=1000.0))
+            .changed();
+        ui.label("Offset of the undeflected ray");
+        changed |= ui.add(Slider::new(&mut self.offset, -2.0..=2.0)).changed();
+        ui.label("Tilt");
+        changed |= ui.add(Slider::new(&mut self.tilt, -0.5..=0.5)).changed();
+
+        ui.strong("Spectrum");
+        let mut continuous = matches!(self.spectrum, SyntheticSpectrum::Continuous { .. });
+        if ui.checkbox(&mut continuous, "continuous").changed() {
+            self.spectrum = if continuous {
+                SyntheticSpectrum::Continuous {
+                    temperature: 3000.0,
+                }
+            } else {
+                SyntheticSettings::default().spectrum
+            };
+            changed = true;
+        }
+        match &mut self.spectrum {
+            SyntheticSpectrum::Continuous { temperature } => {
+                ui.label("Temperature in K");
+                changed |= ui.add(Slider::new(temperature, 1000.0..=10000.0)).changed();
+            }
+            SyntheticSpectrum::Emission(lines) => {
+                let mut remove = None;
+                for (i, (wavelength, intensity)) in lines.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        changed |= ui
+                            .add(
+                                DragValue::new(wavelength)
+                                    .clamp_range(
+                                        SMALLEST_WAVELENGTH as f32..=LARGEST_WAVELENGTH as f32,
+                                    )
+                                    .prefix("λ: ")
+                                    .suffix(" nm"),
+                            )
+                            .changed();
+                        changed |= ui
+                            .add(
+                                DragValue::new(intensity)
+                                    .clamp_range(0.0..=10.0)
+                                    .speed(0.01),
+                            )
+                            .changed();
+                        if ui.button("🗑").clicked() {
+                            remove = Some(i)
+                        }
+                    });
+                }
+                if let Some(i) = remove {
+                    lines.remove(i);
+                    changed = true;
+                }
+                if ui.button("add line").clicked() {
+                    lines.push((500.0, 1.0));
+                    changed = true;
+                }
+            }
+        }
+
+        ui.strong("Sensor");
+        ui.horizontal(|ui| {
+            changed |= ui
+                .add(DragValue::new(&mut self.width).clamp_range(16..=4096))
+                .changed();
+            ui.label("x");
+            changed |= ui
+                .add(DragValue::new(&mut self.height).clamp_range(16..=4096))
+                .changed();
+            ui.label("pixels");
+        });
+        ui.label("Frames per second");
+        changed |= ui.add(Slider::new(&mut self.fps, 1.0..=120.0)).changed();
+        ui.label("Line width in pixels");
+        changed |= ui
+            .add(Slider::new(&mut self.line_width, 0.5..=20.0))
+            .changed();
+        ui.label("Noise");
+        changed |= ui.add(Slider::new(&mut self.noise, 0.0..=0.2)).changed();
+        ui.label("Gamma");
+        changed |= ui.add(Slider::new(&mut self.gamma, 0.2..=3.0)).changed();
+        ui.label("Saturation");
+        changed |= ui
+            .add(Slider::new(&mut self.saturation, 0.05..=5.0))
+            .changed();
+        ui.label("Bit depth");
+        changed |= ui.add(Slider::new(&mut self.bit_depth, 8..=16)).changed();
+        changed |= ui.checkbox(&mut self.colored, "colored").changed();
+        changed
+    }
+}
+
+/// Renders artificial spectrometer images with a known geometry and spectrum.
+pub struct SyntheticSource {
+    settings: SyntheticSettings,
+    /// noise free linear intensity per pixel and channel
+    clean: Vec<f32>,
+    rng: XorShift,
+    last_frame: Option<Instant>,
+}
+
+impl SyntheticSource {
+    pub fn new(settings: SyntheticSettings) -> Result<Self> {
+        if settings.width == 0 || settings.height == 0 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "synthetic image has no pixels",
+            ));
+        }
+        let clean = render(&settings);
+        Ok(Self {
+            settings,
+            clean,
+            rng: XorShift(0x2545_f491_4f6c_dd1d),
+            last_frame: None,
+        })
+    }
+}
+
+impl FrameSource for SyntheticSource {
+    fn name(&self) -> String {
+        format!("synthetic {}x{}", self.settings.width, self.settings.height)
+    }
+
+    fn next_frame(&mut self) -> Result<Image> {
+        if let Some(last) = self.last_frame {
+            let interval = Duration::from_secs_f32(1.0 / self.settings.fps);
+            let elapsed = last.elapsed();
+            if elapsed < interval {
+                std::thread::sleep(interval - elapsed)
+            }
+        }
+        self.last_frame = Some(Instant::now());
+
+        let settings = &self.settings;
+        let full_scale = ((1u32 << settings.bit_depth) - 1) as f32;
+        let data = self
+            .clean
+            .iter()
+            .map(|val| {
+                let val = val / settings.saturation + settings.noise * self.rng.next_gaussian();
+                let val = val.clamp(0.0, 1.0).powf(1.0 / settings.gamma);
+                (val * full_scale).round() as u16
+            })
+            .collect();
+        Ok(Image::new(
+            settings.width as usize,
+            settings.height as usize,
+            data,
+            settings.bit_depth,
+        ))
+    }
+}
+
+fn render(settings: &SyntheticSettings) -> Vec<f32> {
+    let width = settings.width as usize;
+    let height = settings.height as usize;
+    let spectral = settings.spectral_lines();
+    let sigma = settings.line_width;
+
+    // every entry is (wavelength, amplitude of the gaussian profile)
+    let samples: Vec<(f32, f32)> = match &settings.spectrum {
+        SyntheticSpectrum::Emission(lines) => lines.clone(),
+        SyntheticSpectrum::Continuous { temperature } => {
+            let max = (SMALLEST_WAVELENGTH..=LARGEST_WAVELENGTH)
+                .map(|wl| planck(wl as f32, *temperature))
+                .fold(0.0, f32::max);
+            let mut samples = Vec::new();
+            let mut wavelength = SMALLEST_WAVELENGTH as f32;
+            while wavelength <= LARGEST_WAVELENGTH as f32 {
+                samples.push((wavelength, planck(wavelength, *temperature) / max));
+                wavelength += RENDER_STEP;
+            }
+            samples
+        }
+    };
+    let continuous = matches!(settings.spectrum, SyntheticSpectrum::Continuous { .. });
+
+    let mut clean = vec![0.0; width * height * 3];
+    for row in 0..height {
+        let y = (row as f32 + 0.5) / height as f32;
+        for (wavelength, intensity) in samples.iter() {
+            let line = spectral.line_with_wavelength(*wavelength);
+            let x = (line.start.0 + y * (line.end.0 - line.start.0)) * width as f32;
+            let amplitude = if continuous {
+                // spread the sample so that neighbouring samples add up to the intensity
+                let next = spectral.line_with_wavelength(wavelength + RENDER_STEP);
+                let next_x = (next.start.0 + y * (next.end.0 - next.start.0)) * width as f32;
+                intensity * (next_x - x).abs() / ((2.0 * PI).sqrt() * sigma)
+            } else {
+                *intensity
+            };
+            if !x.is_finite() {
+                continue;
+            }
+            let color = if settings.colored {
+                wavelength_to_rgb(*wavelength)
+            } else {
+                [1.0, 1.0, 1.0]
+            };
+            let from = (x - 4.0 * sigma).floor().max(0.0) as usize;
+            let to = ((x + 4.0 * sigma).ceil().max(0.0) as usize).min(width);
+            for col in from..to {
+                let dx = col as f32 + 0.5 - x;
+                let val = amplitude * (-dx * dx / (2.0 * sigma * sigma)).exp();
+                let index = 3 * (row * width + col);
+                for (channel, factor) in color.iter().enumerate() {
+                    clean[index + channel] += val * factor;
+                }
+            }
+        }
+    }
+    clean
+}
+
+/// black body radiation, wavelength in nm and temperature in K, not normalised
+fn planck(wavelength: f32, temperature: f32) -> f32 {
+    // h * c / k in nm * K
+    const HC_OVER_K: f32 = 1.438_777e7;
+    let wl = wavelength / 1000.0;
+    1.0 / (wl.powi(5) * ((HC_OVER_K / (wavelength * temperature)).exp() - 1.0))
+}
+
+/// rough approximation of the colour of monochromatic light
+fn wavelength_to_rgb(wavelength: f32) -> [f32; 3] {
+    let wl = wavelength;
+    let [r, g, b] = if wl < 440.0 {
+        [((440.0 - wl) / 60.0).min(1.0), 0.0, 1.0]
+    } else if wl < 490.0 {
+        [0.0, (wl - 440.0) / 50.0, 1.0]
+    } else if wl < 510.0 {
+        [0.0, 1.0, (510.0 - wl) / 20.0]
+    } else if wl < 580.0 {
+        [(wl - 510.0) / 70.0, 1.0, 0.0]
+    } else if wl < 645.0 {
+        [1.0, (645.0 - wl) / 65.0, 0.0]
+    } else {
+        [1.0, 0.0, 0.0]
+    };
+    let falloff = if wl < 420.0 {
+        0.3 + 0.7 * ((wl - 380.0) / 40.0).max(0.0)
+    } else if wl > 700.0 {
+        0.3 + 0.7 * ((750.0 - wl) / 50.0).max(0.0)
+    } else {
+        1.0
+    };
+    [r * falloff, g * falloff, b * falloff]
+}
+
+/// small pseudo random number generator, good enough for sensor noise
+struct XorShift(u64);
+
+impl XorShift {
+    fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// standard normal distributed number using the Box-Muller transform
+    fn next_gaussian(&mut self) -> f32 {
+        let u1 = self.next_f32().max(f32::MIN_POSITIVE);
+        let u2 = self.next_f32();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::calibration_module::Line;
+
+    /// wavelengths of the rendered emission lines
+    pub(crate) const WAVELENGTHS: [f32; 4] = [436.0, 488.0, 546.0, 611.0];
+
+    /// settings that differ from the initial guesses of the calibration
+    pub(crate) fn settings() -> SyntheticSettings {
+        SyntheticSettings {
+            angle: 20.0,
+            distance_to_sensor: 1.2,
+            offset: 0.45,
+            tilt: 0.08,
+            spectrum: SyntheticSpectrum::Emission(
+                WAVELENGTHS
+                    .iter()
+                    .enumerate()
+                    .map(|(i, wavelength)| (*wavelength, 1.0 - 0.1 * i as f32))
+                    .collect(),
+            ),
+            noise: 0.002,
+            ..SyntheticSettings::default()
+        }
+    }
+
+    pub(crate) fn frame(settings: &SyntheticSettings) -> Image {
+        SyntheticSource::new(settings.clone())
+            .unwrap()
+            .next_frame()
+            .unwrap()
+    }
+
+    /// positions of the peaks in `row` from left to right, refined with a parabola
+    fn peaks(img: &Image, row: usize) -> Vec<f32> {
+        let profile: Vec<f32> = (0..img.width)
+            .map(|x| img.lightness(x, row).unwrap())
+            .collect();
+        let max = profile.iter().cloned().fold(0.0, f32::max);
+        (1..img.width - 1)
+            .filter(|&x| {
+                profile[x] > max / 2.0
+                    && profile[x] >= profile[x - 1]
+                    && profile[x] > profile[x + 1]
+            })
+            .map(|x| {
+                let (left, center, right) = (profile[x - 1], profile[x], profile[x + 1]);
+                let shift = 0.5 * (left - right) / (left - 2.0 * center + right);
+                (x as f32 + 0.5 + shift) / img.width as f32
+            })
+            .collect()
+    }
+
+    /// Calibration lines through the peaks in the first and the last row, the way they
+    /// would be drawn by hand. Longer wavelengths are deflected further to the left.
+    pub(crate) fn measure_lines(img: &Image) -> Vec<(u16, Line)> {
+        let top = peaks(img, 0);
+        let bottom = peaks(img, img.height - 1);
+        assert_eq!(top.len(), WAVELENGTHS.len());
+        assert_eq!(bottom.len(), WAVELENGTHS.len());
+        let (y_top, y_bottom) = (0.5 / img.height as f32, 1.0 - 0.5 / img.height as f32);
+        WAVELENGTHS
+            .iter()
+            .zip(top.iter().rev().zip(bottom.iter().rev()))
+            .map(|(wavelength, (x_top, x_bottom))| {
+                (
+                    *wavelength as u16,
+                    Line {
+                        start: (*x_top, y_top),
+                        end: (*x_bottom, y_bottom),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lines_are_rendered_where_the_geometry_puts_them() {
+        let settings = settings();
+        let img = frame(&settings);
+        let spectral = settings.spectral_lines();
+        for (wavelength, line) in measure_lines(&img) {
+            let expected = spectral.line_with_wavelength(wavelength as f32);
+            let x_top = expected.start.0 + line.start.1 * (expected.end.0 - expected.start.0);
+            assert!((line.start.0 - x_top).abs() < 0.5 / img.width as f32);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    time::Duration,
+};
+
+use v4l::{buffer, io::traits::CaptureStream, prelude::*, video::Capture, Format};
+
+use super::{bayer, decode, frame_ranges, profile, Crop, DriverStamp, FrameSource, Image};
+
+/// added to twice the frame interval before waiting for a frame times out
+const STREAM_TIMEOUT_MARGIN: Duration = Duration::from_millis(500);
+/// the timeout grows up to this if frames arrive slower than the interval, e.g. with
+/// exposures longer than it
+const MAX_STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct V4l2Source {
+    name: String,
+    /// same as the key of the camera profile
+    key: String,
+    stream: MmapStream<'static>,
+    format: Format,
+    demosaic: bool,
+    /// set if the driver already crops the frames
+    crop: Option<Crop>,
+    /// longest wait for a frame, so the acquisition can notice that it was stopped
+    timeout: Duration,
+    /// the last wait timed out while its buffer was still queued in the driver
+    stalled: bool,
+}
+
+impl V4l2Source {
+    /// raw bayer frames are only interpolated to rgb if `demosaic` is set, `crop` tells where
+    /// the frames lie in the full frame if the driver crops them
+    pub fn new(camera: &Device, demosaic: bool, crop: Option<Crop>) -> Result<Self> {
+        let name = match camera.query_caps() {
+            Ok(caps) => caps.card,
+            Err(_) => "V4L2 camera".to_string(),
+        };
+        let key = profile::profile_key(camera).unwrap_or_else(|_| name.clone());
+        let format = camera.format()?;
+        let interval = match camera.params() {
+            Ok(params) => Duration::from_secs_f64(frame_ranges::seconds(params.interval)),
+            Err(_) => Duration::ZERO,
+        };
+        let timeout = (interval * 2 + STREAM_TIMEOUT_MARGIN).min(MAX_STREAM_TIMEOUT);
+        let mut stream = MmapStream::with_buffers(camera, buffer::Type::VideoCapture, 5)?;
+        stream.set_timeout(timeout);
+        Ok(Self {
+            name,
+            key,
+            stream,
+            format,
+            demosaic,
+            crop,
+            timeout,
+            stalled: false,
+        })
+    }
+}
+
+impl FrameSource for V4l2Source {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn key(&self) -> String {
+        self.key.clone()
+    }
+
+    fn next_frame(&mut self) -> Result<Image> {
+        if self.stalled {
+            // `next` would queue the buffer of the timed out wait a second time, so that frame
+            // is taken out and dropped first
+            CaptureStream::dequeue(&mut self.stream)?;
+            self.stalled = false;
+        }
+        let (buf, meta) = match self.stream.next() {
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                self.stalled = true;
+                self.timeout = (self.timeout * 2).min(MAX_STREAM_TIMEOUT);
+                self.stream.set_timeout(self.timeout);
+                return Err(err);
+            }
+            result => result?,
+        };
+        let used = (meta.bytesused as usize).min(buf.len());
+        let mut img = decode::decode(&buf[..used], &self.format).map_err(|err| {
+            Error::new(
+                err.kind(),
+                format!("could not load image frame {}: {}", meta.sequence, err),
+            )
+        })?;
+        if self.demosaic {
+            bayer::demosaic(&mut img)
+        }
+        img.crop = self.crop;
+        img.stamp = Some(DriverStamp {
+            sequence: meta.sequence,
+            timestamp: Duration::new(
+                meta.timestamp.sec.max(0) as u64,
+                (meta.timestamp.usec.max(0) * 1000) as u32,
+            ),
+        });
+        Ok(img)
+    }
+}
@@ -8,12 +8,19 @@ pub trait Cost {
     fn cost(&self, parameters: &[f32]) -> f32;
 }
 
+/// steps after which the progress of [`search_minimum`] is checked
+const PROGRESS_INTERVAL: u32 = 1000;
+
+/// Stops once the cost is below `acceptable_error` or when it improved by less than the
+/// fraction `min_improvement` over the last [`PROGRESS_INTERVAL`] steps, as noisy data may
+/// never reach the acceptable error.
 pub fn search_minimum<P>(
     problem: P,
     initial_params: Vec<f32>,
     max_iterations: u32,
     initial_step_size: f32,
     acceptable_error: f32,
+    min_improvement: f32,
 ) -> Vec<f32>
 where
     P: Gradient + Cost,
@@ -24,13 +31,20 @@ where
     let tau = 0.8; // e (0, 1)
     let mut last_step_size = initial_step_size; // this value should be better determined TODO
     let mut parameters = initial_params;
+    let mut checked_cost = f32::INFINITY;
     for i in 0..max_iterations {
         let cost = problem.cost(&parameters);
         if cost < acceptable_error {
             break;
         }
+        if i % PROGRESS_INTERVAL == 0 {
+            if cost > checked_cost * (1.0 - min_improvement) {
+                break;
+            }
+            checked_cost = cost;
+        }
         let gradient = problem.gradient(&parameters);
-        if i % 1000 == 0 {
+        if i % PROGRESS_INTERVAL == 0 {
             println!("at step {} the cost is {}", i, problem.cost(&parameters));
             println!("alpha = {}", parameters[0].atan() / PI * 360.0);
             println!("distance to sensor / sensor width = {}", parameters[1]);
@@ -89,3 +103,39 @@ pub fn scale(x: Vec<f32>, factor: f32) -> Vec<f32> {
 //     let y_offset = mean_y - slope * mean_x;
 //     LinearRegression { slope, y_offset }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, rc::Rc};
+
+    /// parabola with a minimum of one at `x = 2`, counting how often it is evaluated
+    struct Parabola {
+        evaluations: Rc<Cell<u32>>,
+    }
+
+    impl Cost for Parabola {
+        fn cost(&self, parameters: &[f32]) -> f32 {
+            self.evaluations.set(self.evaluations.get() + 1);
+            (parameters[0] - 2.0).powi(2) + 1.0
+        }
+    }
+
+    impl Gradient for Parabola {
+        fn gradient(&self, parameters: &[f32]) -> Vec<f32> {
+            vec![2.0 * (parameters[0] - 2.0), 0.0, 0.0]
+        }
+    }
+
+    #[test]
+    fn search_stops_once_the_cost_no_longer_improves() {
+        let evaluations = Rc::new(Cell::new(0));
+        let problem = Parabola {
+            evaluations: evaluations.clone(),
+        };
+        // the acceptable error can't be reached
+        let params = search_minimum(problem, vec![0.0, 1.0, 0.0], 1_000_000, 0.1, 1e-7, 0.01);
+        assert!((params[0] - 2.0).abs() < 1e-3);
+        assert!(evaluations.get() < 100_000, "{}", evaluations.get());
+    }
+}
@@ -13,6 +13,7 @@ mod spectrum_module;
 mod tracer_module;
 
 pub use app::SpeckApp;
+pub use camera_module::pipe_source::{PipeSettings, USAGE};
 
 pub const SMALLEST_WAVELENGTH: u16 = 380;
 pub const LARGEST_WAVELENGTH: u16 = 750;
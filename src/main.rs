@@ -1,6 +1,14 @@
 fn main() {
     tracing_subscriber::fmt::init();
 
+    let pipe = match speckmeter::PipeSettings::from_args(std::env::args().skip(1)) {
+        Ok(pipe) => pipe,
+        Err(err) => {
+            eprintln!("{}\n{}", err, speckmeter::USAGE);
+            std::process::exit(2)
+        }
+    };
+
     let native_options = eframe::NativeOptions {
         icon_data: Some(load_icon("./icon.png")), // an example
         ..Default::default()
@@ -8,7 +16,7 @@ fn main() {
     eframe::run_native(
         "Speckmeter",
         native_options,
-        Box::new(|cc| Box::new(speckmeter::SpeckApp::new(cc))),
+        Box::new(|cc| Box::new(speckmeter::SpeckApp::new(cc, pipe))),
     );
 }
 
@@ -1,8 +1,11 @@
+mod channels;
+mod extraction;
+
 use std::path::{Path, PathBuf};
 
 use egui::{
-    plot::{Plot, PlotPoints},
-    Context, Ui,
+    plot::{Line, Plot, PlotPoints},
+    Color32, Context, Ui,
 };
 use itertools::Itertools;
 use log::{error, info, warn};
@@ -10,16 +13,32 @@ use native_dialog::FileDialog;
 
 use crate::{
     calibration_module::CalibrationModule,
-    camera_module::{CameraStream, Image},
+    camera_module::{CameraStream, DriverStamp, Exposure, Image},
     csv, LARGEST_WAVELENGTH, SMALLEST_WAVELENGTH,
 };
+pub use channels::{ChannelResponse, ChannelSettings, Combination};
+pub use extraction::{Aperture, Extraction};
 
 pub struct SpectrographModule {
     take_average: usize,
     reference: Option<AbsSpectrograph>,
     current: Option<AbsSpectrograph>,
     spec_buf: Vec<AbsSpectrograph>,
+    /// sequence number of the last frame added to `spec_buf`
+    last_sequence: Option<u64>,
+    /// divide by the exposure time so spectra at different exposures can be compared
+    per_second: bool,
+    /// spectra of the current exposure bracket, at most one per exposure
+    bracket_parts: Vec<AbsSpectrograph>,
+    /// number of exposures merged into the last hdr spectrum
+    hdr_exposures: Option<usize>,
     relative: bool,
+    /// how the channels are reduced to the intensity
+    channel_settings: ChannelSettings,
+    /// where the light of every wavelength is collected
+    aperture: Aperture,
+    /// plot the red, green and blue spectra next to the combined one
+    show_channels: bool,
     start: f32,
     stop: f32,
     step: f32,
@@ -33,13 +52,13 @@ impl SpectrographModule {
     pub fn display(
         &mut self,
         ctx: &Context,
-        width: u32,
-        height: u32,
+        stream: &mut CameraStream,
         calib: &mut CalibrationModule,
     ) {
-        egui::SidePanel::right("spectrograph_opts").show(ctx, |ui| self.side_panel(ui));
+        let strips = calib.background_strips().len();
+        egui::SidePanel::right("spectrograph_opts").show(ctx, |ui| self.side_panel(ui, strips));
 
-        egui::CentralPanel::default().show(ctx, |ui| self.main_view(ui, width, height, calib));
+        egui::CentralPanel::default().show(ctx, |ui| self.main_view(ui, stream, calib));
     }
 }
 
@@ -47,35 +66,67 @@ impl SpectrographModule {
     pub fn main_view(
         &mut self,
         ui: &mut Ui,
-        width: u32,
-        height: u32,
+        stream: &mut CameraStream,
         calib: &mut CalibrationModule,
     ) {
-        if let Some(img) = CameraStream::get_img(width, height) {
-            if let Some(spec) =
-                AbsSpectrograph::from_img(&img, calib, self.start, self.stop, self.step)
-            {
-                self.spec_buf.push(spec);
-            } else {
-                warn!("could not generate spectrograph")
+        for frame in stream.frames_since(self.last_sequence) {
+            self.last_sequence = Some(frame.sequence);
+            let mut spec = match AbsSpectrograph::from_img(
+                &frame.img,
+                calib,
+                self.start,
+                self.stop,
+                self.step,
+                &self.channel_settings,
+                &self.aperture,
+            ) {
+                Some(spec) => spec,
+                None => {
+                    warn!("could not generate spectrograph");
+                    continue;
+                }
+            };
+            match stream.bracket() {
+                Some(bracket) => {
+                    if let Some(merged) = self.add_to_bracket(spec, bracket) {
+                        self.spec_buf.push(merged)
+                    }
+                }
+                None => {
+                    self.hdr_exposures = None;
+                    if self.per_second {
+                        match spec.exposure {
+                            Some(exposure) => spec.scale(1.0 / exposure.seconds()),
+                            None => continue,
+                        }
+                    }
+                    self.spec_buf.push(spec);
+                }
             }
         }
 
-        if self.spec_buf.len() >= self.take_average {
+        // frames arrive independently of the repaints, so there may be none yet
+        if !self.spec_buf.is_empty() && self.spec_buf.len() >= self.take_average {
             self.current = Some(average_spectrograph(&self.spec_buf));
             self.spec_buf = Vec::new();
         }
 
         match self.current.as_ref() {
             Some(spec) => {
+                let header = self.csv_header(
+                    spec.stamp,
+                    stream.dropped_frames(),
+                    calib.background_strips().len(),
+                );
                 if self.relative {
                     match self.reference.as_ref() {
                         Some(reference) => {
                             let spec = RelativeSpectrum::new(spec, reference);
-                            spec.show(ui);
+                            saturation_warning(ui, spec.saturated_count(), self.save_next);
+                            spec.show(ui, self.show_channels);
                             if self.save_next {
                                 match self.path.as_ref() {
-                                    Some(path) => match spec.write_to_csv(path, &self.comment) {
+                                    Some(path) => match spec.write_to_csv(path, &header) {
                                         Ok(_) => info!("saved file succesfully to {:?}", path),
                                         Err(err) => error!("failed to save file Error: {}", err),
                                     },
@@ -91,9 +142,10 @@ impl SpectrographModule {
                         }
                     }
                 } else {
+                    saturation_warning(ui, spec.saturated_count(), self.save_next);
                     if self.save_next {
                         match self.path.as_ref() {
-                            Some(path) => match spec.write_to_csv(path, &self.comment) {
+                            Some(path) => match spec.write_to_csv(path, &header) {
                                 Ok(_) => info!("saved file succesfully to {:?}", path),
                                 Err(err) => error!("failed to save file Error: {}", err),
                             },
@@ -103,7 +155,7 @@ impl SpectrographModule {
                         }
                         self.save_next = false
                     }
-                    spec.show(ui)
+                    spec.show(ui, self.show_channels)
                 }
             }
             None => warn!("no current image available"),
@@ -111,7 +163,85 @@ impl SpectrographModule {
         ui.ctx().request_repaint()
     }
 
-    pub fn side_panel(&mut self, ui: &mut Ui) {
+    /// Collects the spectra of a bracket and returns the merged one once every exposure
+    /// was seen, frames taken while the exposure was changing are ignored.
+    fn add_to_bracket(
+        &mut self,
+        spec: AbsSpectrograph,
+        bracket: &[Exposure],
+    ) -> Option<AbsSpectrograph> {
+        let exposure = spec
+            .exposure
+            .filter(|exposure| bracket.contains(exposure))?;
+        self.bracket_parts
+            .retain(|part| part.exposure != Some(exposure) && part.compare(&spec));
+        self.bracket_parts.push(spec);
+        if self.bracket_parts.len() < bracket.len() {
+            return None;
+        }
+        let merged = merge_hdr(&self.bracket_parts);
+        self.hdr_exposures = Some(self.bracket_parts.len());
+        self.bracket_parts.clear();
+        Some(merged)
+    }
+
+    /// comment of the user followed by the frame the spectrum comes from and how it was
+    /// extracted
+    fn csv_header(&self, stamp: Option<DriverStamp>, dropped: u64, strips: usize) -> String {
+        let mut header = self.comment.clone();
+        header.push_str(&format!("\nextraction: {}", self.aperture.extraction));
+        if self.aperture.extraction == Extraction::Line {
+            header.push_str(&format!("\ninterpolation: {}", self.aperture.sampler));
+        } else {
+            let (top, bottom) = self.aperture.rows();
+            header.push_str(&format!(
+                "\naperture: {:.3} to {:.3} of the frame height",
+                top, bottom
+            ));
+        }
+        if self.aperture.subtract_background && strips > 0 {
+            header.push_str(&format!(
+                "\nbackground: subtracted, estimated from {} strips",
+                strips
+            ));
+        }
+        header.push_str(&format!(
+            "\nchannels combined by: {}",
+            self.channel_settings.combination
+        ));
+        if self.channel_settings.combination == Combination::Weighted {
+            let noise = self.channel_settings.noise;
+            header.push_str(&format!(
+                "\nchannel response: {}\nfull well: {} e, read noise: {} e",
+                match self.channel_settings.response.is_measured() {
+                    true => "measured",
+                    false => "typical sensor",
+                },
+                noise.full_well,
+                noise.read_noise
+            ));
+        }
+        if let Some(stamp) = stamp {
+            header.push_str(&format!(
+                "\nframe: {} at {:.6} s",
+                stamp.sequence,
+                stamp.timestamp.as_secs_f64()
+            ));
+        }
+        match self.hdr_exposures {
+            Some(count) => header.push_str(&format!(
+                "\nvalues: hdr merged from {} exposures, intensity per second",
+                count
+            )),
+            None if self.per_second => header.push_str("\nvalues: intensity per second"),
+            None => (),
+        }
+        header.push_str(&format!("\ndropped frames: {}", dropped));
+        header
+    }
+
+    /// `strips` is the number of background strips marked in the calibration
+    pub fn side_panel(&mut self, ui: &mut Ui, strips: usize) {
         if ui.button("take reference").clicked() {
             match self.current.as_ref() {
                 Some(spec) => {
@@ -126,7 +256,33 @@ impl SpectrographModule {
             ui.checkbox(&mut self.relative, "relative");
         }
 
-        ui.add(egui::Slider::new(&mut self.take_average, 0..=100));
+        ui.add(egui::Slider::new(&mut self.take_average, 1..=100));
+
+        self.channel_panel(ui);
+        if self.aperture.ui(ui) | self.aperture.background_ui(ui, strips) {
+            self.spec_buf.clear();
+            self.bracket_parts.clear();
+        }
+
+        if let Some(count) = self.hdr_exposures {
+            ui.label(format!(
+                "hdr spectrum merged from {} exposures, in intensity per second",
+                count
+            ));
+        }
+        if ui
+            .checkbox(&mut self.per_second, "divide by exposure time")
+            .on_hover_text("frames without a known exposure are skipped")
+            .changed()
+        {
+            self.spec_buf.clear();
+        }
+        if let Some(spec) = self.current.as_ref() {
+            match spec.exposure {
+                Some(exposure) => ui.label(format!("exposure: {}", exposure)),
+                None => ui.label("exposure: unknown"),
+            };
+        }
 
         ui.label("Additional comment for csv");
         ui.text_edit_multiline(&mut self.comment);
@@ -158,15 +314,109 @@ impl SpectrographModule {
     }
 }
 
+impl SpectrographModule {
+    fn channel_panel(&mut self, ui: &mut Ui) {
+        let mut combination = self.channel_settings.combination;
+        egui::ComboBox::from_label("channels")
+            .selected_text(combination.to_string())
+            .show_ui(ui, |ui| {
+                for option in Combination::ALL {
+                    ui.selectable_value(&mut combination, option, option.to_string());
+                }
+            });
+        let mut changed = combination != self.channel_settings.combination;
+        self.channel_settings.combination = combination;
+        if combination == Combination::Weighted {
+            let measured = self.channel_settings.response.is_measured();
+            match measured {
+                true => ui.label("response measured from a reference"),
+                false => ui.label("response of a typical sensor"),
+            };
+            if ui
+                .add_enabled(
+                    self.current.is_some(),
+                    egui::Button::new("measure response"),
+                )
+                .on_hover_text("point the spectrometer at a broadband lamp such as a halogen bulb")
+                .clicked()
+            {
+                changed |= self.measure_response();
+            }
+            if measured && ui.button("use typical response").clicked() {
+                self.channel_settings.response = ChannelResponse::default();
+                changed = true;
+            }
+            changed |= self.channel_settings.noise_ui(ui);
+        }
+        ui.checkbox(&mut self.show_channels, "show channels");
+        if changed {
+            self.recombine()
+        }
+    }
+
+    /// estimates the response of the channels from the current spectrum
+    fn measure_response(&mut self) -> bool {
+        let spec = match self.current.as_ref() {
+            Some(spec) => spec,
+            None => return false,
+        };
+        let saturated = spec.saturated_count();
+        if saturated > 0 {
+            warn!(
+                "the channel response is wrong where the reference is saturated ({} samples)",
+                saturated
+            )
+        }
+        self.channel_settings.response =
+            ChannelResponse::from_reference(spec.start, spec.step, &spec.channels);
+        info!("measured the channel response");
+        true
+    }
+
+    /// recombines the current spectrum and the reference, spectra of the old
+    /// settings that are still being collected are dropped
+    fn recombine(&mut self) {
+        self.spec_buf.clear();
+        self.bracket_parts.clear();
+        for spec in self.current.iter_mut().chain(self.reference.iter_mut()) {
+            spec.combine(&self.channel_settings)
+        }
+    }
+
+    pub fn channel_settings(&self) -> &ChannelSettings {
+        &self.channel_settings
+    }
+
+    pub fn set_channel_settings(&mut self, settings: ChannelSettings) {
+        self.channel_settings = settings;
+        self.recombine()
+    }
+
+    pub fn aperture(&self) -> &Aperture {
+        &self.aperture
+    }
+
+    pub fn set_aperture(&mut self, aperture: Aperture) {
+        self.aperture = aperture
+    }
+}
+
 impl Default for SpectrographModule {
     fn default() -> Self {
         Self {
             spec_buf: Vec::new(),
+            last_sequence: None,
+            per_second: false,
+            bracket_parts: Vec::new(),
+            hdr_exposures: None,
             take_average: 1,
             reference: None,
             comment: String::new(),
             current: None,
             relative: false,
+            channel_settings: ChannelSettings::default(),
+            aperture: Aperture::default(),
+            show_channels: false,
             start: SMALLEST_WAVELENGTH as f32,
             stop: LARGEST_WAVELENGTH as f32,
             step: 1.0,
@@ -183,6 +433,17 @@ pub struct AbsSpectrograph {
     stop: f32,
     step: f32,
     values: Vec<f32>,
+    /// red, green and blue spectra the values are combined from
+    channels: [Vec<f32>; 3],
+    /// variance of every value, only known for the extraction from an aperture
+    variance: Option<Vec<f32>>,
+    channel_variance: Option<[Vec<f32>; 3]>,
+    /// exposure of the frames, None if unknown or mixed
+    exposure: Option<Exposure>,
+    /// set where a pixel on the line reached the maximum of the sensor
+    saturated: Vec<bool>,
+    /// driver stamp of the newest frame that went into the spectrum
+    stamp: Option<DriverStamp>,
 }
 
 impl AbsSpectrograph {
@@ -192,43 +453,176 @@ impl AbsSpectrograph {
         start: f32,
         stop: f32,
         step: f32,
+        channel_settings: &ChannelSettings,
+        aperture: &Aperture,
     ) -> Option<Self> {
         let lines = calib.get_lines(start, stop, step)?;
 
-        let mut values = Vec::with_capacity(lines.len());
+        let mut channels = [
+            Vec::with_capacity(lines.len()),
+            Vec::with_capacity(lines.len()),
+            Vec::with_capacity(lines.len()),
+        ];
+        let mut channel_variance = None;
+        let mut saturated = Vec::with_capacity(lines.len());
 
-        for line in lines.iter() {
-            values.push(img.read_line_lightness(line));
+        let strips = calib.background_strips().to_vec();
+        let subtract_background = aperture.subtract_background && !strips.is_empty();
+        // the bands reach halfway to the neighbouring wavelengths
+        let edges = match aperture.extraction != Extraction::Line || subtract_background {
+            true => (0..=lines.len())
+                .map(|i| calib.get_line(start + (i as f32 - 0.5) * step))
+                .collect::<Option<Vec<_>>>()?,
+            false => Vec::new(),
+        };
+        let background = subtract_background.then(|| {
+            extraction::estimate_background(img, &edges, &strips, &channel_settings.noise)
+        });
+
+        match aperture.extraction {
+            Extraction::Line => {
+                for (i, line) in lines.iter().enumerate() {
+                    let sample = img.read_line(line, aperture.sampler);
+                    let level = background
+                        .as_ref()
+                        .map(|background| background[i].level)
+                        .unwrap_or_default();
+                    for ((channel, val), level) in
+                        channels.iter_mut().zip(sample.channels).zip(level)
+                    {
+                        // like the aperture, a line without usable pixels collects no light
+                        channel.push(val.map(|val| val - level).unwrap_or(0.0))
+                    }
+                    saturated.push(sample.saturated);
+                }
+            }
+            _ => {
+                let mut variance = channels.clone();
+                let samples =
+                    aperture.extract(img, &edges, &channel_settings.noise, background.as_deref());
+                for sample in samples {
+                    for channel in 0..3 {
+                        channels[channel].push(sample.channels[channel]);
+                        variance[channel].push(sample.variance[channel]);
+                    }
+                    saturated.push(sample.saturated);
+                }
+                channel_variance = Some(variance);
+            }
         }
-        Some(Self {
+        let mut spec = Self {
             start,
             stop,
             step,
-            values,
-        })
+            values: Vec::new(),
+            channels,
+            variance: None,
+            channel_variance,
+            exposure: img.exposure,
+            saturated,
+            stamp: img.stamp,
+        };
+        spec.combine(channel_settings);
+        Some(spec)
+    }
+
+    /// recomputes the values and their variance from the channels
+    pub fn combine(&mut self, channel_settings: &ChannelSettings) {
+        let (values, variance): (Vec<f32>, Vec<Option<f32>>) = (0..self.channels[0].len())
+            .map(|i| {
+                let rgb = [
+                    self.channels[0][i],
+                    self.channels[1][i],
+                    self.channels[2][i],
+                ];
+                let variance = self
+                    .channel_variance
+                    .as_ref()
+                    .map(|variance| [variance[0][i], variance[1][i], variance[2][i]]);
+                let wavelength = self.start + i as f32 * self.step;
+                channel_settings.combine(rgb, variance, wavelength)
+            })
+            .unzip();
+        self.values = values;
+        self.variance = self
+            .channel_variance
+            .as_ref()
+            .and_then(|_| variance.into_iter().collect());
     }
 
     pub fn add(&mut self, other: &Self) {
         assert_eq!(self.start, other.start);
         assert_eq!(self.step, other.step);
         assert_eq!(self.stop, other.stop);
+        if self.exposure != other.exposure {
+            self.exposure = None
+        }
+        if other.stamp.is_some() {
+            self.stamp = other.stamp
+        }
+        self.saturated
+            .iter_mut()
+            .zip(other.saturated.iter())
+            .for_each(|(a, b)| *a |= b);
         self.values = self
             .values
             .iter()
             .zip(other.values.iter())
             .map(|(x1, x2)| x1 + x2)
             .collect();
+        for (channel, other) in self.channels.iter_mut().zip(other.channels.iter()) {
+            channel.iter_mut().zip(other).for_each(|(a, b)| *a += b)
+        }
+        // the sum of independent samples has the sum of their variances
+        match (self.variance.as_mut(), other.variance.as_ref()) {
+            (Some(variance), Some(other)) => {
+                variance.iter_mut().zip(other).for_each(|(a, b)| *a += b)
+            }
+            _ => self.variance = None,
+        }
+        match (
+            self.channel_variance.as_mut(),
+            other.channel_variance.as_ref(),
+        ) {
+            (Some(variance), Some(other)) => {
+                for (channel, other) in variance.iter_mut().zip(other.iter()) {
+                    channel.iter_mut().zip(other).for_each(|(a, b)| *a += b)
+                }
+            }
+            _ => self.channel_variance = None,
+        }
     }
 
     pub fn scale(&mut self, factor: f32) {
-        self.values.iter_mut().for_each(|x| *x *= factor)
+        self.values.iter_mut().for_each(|x| *x *= factor);
+        self.channels
+            .iter_mut()
+            .flatten()
+            .for_each(|x| *x *= factor);
+        let squared = factor * factor;
+        self.variance
+            .iter_mut()
+            .flatten()
+            .for_each(|x| *x *= squared);
+        self.channel_variance
+            .iter_mut()
+            .flatten()
+            .flatten()
+            .for_each(|x| *x *= squared);
+    }
+
+    pub fn saturated_count(&self) -> usize {
+        self.saturated
+            .iter()
+            .filter(|saturated| **saturated)
+            .count()
     }
 
     pub fn compare(&self, other: &Self) -> bool {
         self.start == other.start && self.stop == other.stop && self.step == other.step
     }
 
-    pub fn show(&self, ui: &mut Ui) {
+    pub fn show(&self, ui: &mut Ui, show_channels: bool) {
         let points: PlotPoints = self
             .values
             .iter()
@@ -243,19 +637,51 @@ impl AbsSpectrograph {
             .allow_zoom(false)
             .include_y(0.0)
             .include_y(1.0)
-            .show(ui, |plot_ui| plot_ui.line(egui::plot::Line::new(points)));
+            .show(ui, |plot_ui| {
+                for region in
+                    saturated_regions(self.start, self.step, &self.values, &self.saturated)
+                {
+                    plot_ui.polygon(region)
+                }
+                if show_channels {
+                    for line in channel_lines(self.start, self.step, &self.channels) {
+                        plot_ui.line(line)
+                    }
+                }
+                plot_ui.line(Line::new(points).name("combined"))
+            });
     }
 
     pub fn write_to_csv(&self, path: impl AsRef<Path>, header: &str) -> std::io::Result<()> {
         let wavelengths = (0..self.values.len())
             .map(|x| x as f32 * self.step + self.start)
             .collect_vec();
-        csv::write_f32_csv(
-            path,
-            vec!["wavelengths [nm]".to_string(), "intensity".to_string()],
-            vec![wavelengths, self.values.clone()],
-            header,
-        )
+        let header = match self.exposure {
+            Some(exposure) => format!("{}\nexposure: {}", header, exposure),
+            None => header.to_string(),
+        };
+        let [red, green, blue] = self.channels.clone();
+        let mut keys = vec![
+            "wavelengths [nm]".to_string(),
+            "intensity".to_string(),
+            "saturated".to_string(),
+            "red".to_string(),
+            "green".to_string(),
+            "blue".to_string(),
+        ];
+        let mut valss = vec![
+            wavelengths,
+            self.values.clone(),
+            saturation_column(&self.saturated),
+            red,
+            green,
+            blue,
+        ];
+        if let Some(variance) = self.variance.as_ref() {
+            keys.push("variance".to_string());
+            valss.push(variance.clone());
+        }
+        csv::write_f32_csv(path, keys, valss, &header)
     }
 }
 
@@ -263,6 +689,11 @@ pub struct RelativeSpectrum {
     start: f32,
     step: f32,
     values: Vec<f32>,
+    channels: [Vec<f32>; 3],
+    /// propagated from the variances of the spectrum and the reference
+    variance: Option<Vec<f32>>,
+    /// saturated in either the spectrum or the reference
+    saturated: Vec<bool>,
 }
 
 impl RelativeSpectrum {
@@ -277,10 +708,44 @@ impl RelativeSpectrum {
                 .zip(reference.values.iter())
                 .map(|(val, refer)| val / refer)
                 .collect(),
+            channels: [0, 1, 2].map(|channel| {
+                values.channels[channel]
+                    .iter()
+                    .zip(reference.channels[channel].iter())
+                    .map(|(val, refer)| val / refer)
+                    .collect()
+            }),
+            variance: match (values.variance.as_ref(), reference.variance.as_ref()) {
+                (Some(variance), Some(reference_variance)) => Some(
+                    (0..values.values.len())
+                        .map(|i| {
+                            let ratio = values.values[i] / reference.values[i];
+                            ratio
+                                * ratio
+                                * (variance[i] / values.values[i].powi(2)
+                                    + reference_variance[i] / reference.values[i].powi(2))
+                        })
+                        .collect(),
+                ),
+                _ => None,
+            },
+            saturated: values
+                .saturated
+                .iter()
+                .zip(reference.saturated.iter())
+                .map(|(val, refer)| *val || *refer)
+                .collect(),
         }
     }
 
-    pub fn show(&self, ui: &mut Ui) {
+    pub fn saturated_count(&self) -> usize {
+        self.saturated
+            .iter()
+            .filter(|saturated| **saturated)
+            .count()
+    }
+
+    pub fn show(&self, ui: &mut Ui, show_channels: bool) {
         let points: PlotPoints = self
             .values
             .iter()
@@ -295,19 +760,47 @@ impl RelativeSpectrum {
             .allow_zoom(false)
             .include_y(0.0)
             .include_y(1.0)
-            .show(ui, |plot_ui| plot_ui.line(egui::plot::Line::new(points)));
+            .show(ui, |plot_ui| {
+                for region in
+                    saturated_regions(self.start, self.step, &self.values, &self.saturated)
+                {
+                    plot_ui.polygon(region)
+                }
+                if show_channels {
+                    for line in channel_lines(self.start, self.step, &self.channels) {
+                        plot_ui.line(line)
+                    }
+                }
+                plot_ui.line(Line::new(points).name("combined"))
+            });
     }
 
     pub fn write_to_csv(&self, path: impl AsRef<Path>, header: &str) -> std::io::Result<()> {
         let wavelengths = (0..self.values.len())
             .map(|x| x as f32 * self.step + self.start)
             .collect_vec();
-        csv::write_f32_csv(
-            path,
-            vec!["wavelengths [nm]".to_string(), "intensity".to_string()],
-            vec![wavelengths, self.values.clone()],
-            header,
-        )
+        let [red, green, blue] = self.channels.clone();
+        let mut keys = vec![
+            "wavelengths [nm]".to_string(),
+            "intensity".to_string(),
+            "saturated".to_string(),
+            "red".to_string(),
+            "green".to_string(),
+            "blue".to_string(),
+        ];
+        let mut valss = vec![
+            wavelengths,
+            self.values.clone(),
+            saturation_column(&self.saturated),
+            red,
+            green,
+            blue,
+        ];
+        if let Some(variance) = self.variance.as_ref() {
+            keys.push("variance".to_string());
+            valss.push(variance.clone());
+        }
+        csv::write_f32_csv(path, keys, valss, header)
     }
 }
 
@@ -320,3 +813,247 @@ fn average_spectrograph(graphs: &Vec<AbsSpectrograph>) -> AbsSpectrograph {
     graph1.scale(factor);
     graph1
 }
+
+/// Merges spectra taken at different exposures into one in intensity per second.
+/// Every sample is the sum of all unsaturated values divided by their total exposure time,
+/// which weights longer exposures higher as they have the better signal to noise ratio.
+/// Samples that are saturated in all exposures are taken from the shortest one.
+fn merge_hdr(parts: &[AbsSpectrograph]) -> AbsSpectrograph {
+    let shortest = parts
+        .iter()
+        .min_by_key(|part| part.exposure.map(|exposure| exposure.time))
+        .expect("bracket should not be empty");
+    let mut merged = shortest.clone();
+    merged.exposure = None;
+    if parts.iter().any(|part| part.variance.is_none()) {
+        merged.variance = None;
+    }
+    if parts.iter().any(|part| part.channel_variance.is_none()) {
+        merged.channel_variance = None;
+    }
+    for i in 0..merged.values.len() {
+        let mut sum = 0.0;
+        let mut channel_sums = [0.0; 3];
+        let mut variance = 0.0;
+        let mut channel_variances = [0.0; 3];
+        let mut time = 0.0;
+        for part in parts.iter().filter(|part| !part.saturated[i]) {
+            if let Some(exposure) = part.exposure {
+                sum += part.values[i];
+                for (channel_sum, channel) in channel_sums.iter_mut().zip(part.channels.iter()) {
+                    *channel_sum += channel[i];
+                }
+                if let Some(part_variance) = part.variance.as_ref() {
+                    variance += part_variance[i];
+                }
+                if let Some(part_variance) = part.channel_variance.as_ref() {
+                    for (total, channel) in channel_variances.iter_mut().zip(part_variance.iter()) {
+                        *total += channel[i];
+                    }
+                }
+                time += exposure.seconds();
+            }
+        }
+        if time > 0.0 {
+            merged.values[i] = sum / time;
+            for (channel, channel_sum) in merged.channels.iter_mut().zip(channel_sums) {
+                channel[i] = channel_sum / time;
+            }
+            if let Some(merged_variance) = merged.variance.as_mut() {
+                merged_variance[i] = variance / (time * time);
+            }
+            if let Some(merged_variance) = merged.channel_variance.as_mut() {
+                for (channel, total) in merged_variance.iter_mut().zip(channel_variances) {
+                    channel[i] = total / (time * time);
+                }
+            }
+            merged.saturated[i] = false;
+        } else if let Some(exposure) = shortest.exposure {
+            let seconds = exposure.seconds();
+            merged.values[i] = shortest.values[i] / seconds;
+            for channel in merged.channels.iter_mut() {
+                channel[i] /= seconds;
+            }
+            if let Some(merged_variance) = merged.variance.as_mut() {
+                merged_variance[i] /= seconds * seconds;
+            }
+            for channel in merged.channel_variance.iter_mut().flatten() {
+                channel[i] /= seconds * seconds;
+            }
+        }
+    }
+    merged
+}
+
+/// the red, green and blue spectra in their colours
+fn channel_lines(start: f32, step: f32, channels: &[Vec<f32>; 3]) -> Vec<Line> {
+    let colors = [
+        ("red", Color32::RED),
+        ("green", Color32::GREEN),
+        ("blue", Color32::LIGHT_BLUE),
+    ];
+    channels
+        .iter()
+        .zip(colors)
+        .map(|(values, (name, color))| {
+            let points: PlotPoints = values
+                .iter()
+                .enumerate()
+                .map(|(i, val)| [start as f64 + i as f64 * step as f64, *val as f64])
+                .collect();
+            Line::new(points).name(name).color(color)
+        })
+        .collect()
+}
+
+fn saturation_warning(ui: &mut Ui, count: usize, saving: bool) {
+    if count == 0 {
+        return;
+    }
+    ui.colored_label(
+        egui::Color32::RED,
+        format!("⚠ {} samples are saturated", count),
+    );
+    if saving {
+        warn!("the saved spectrum has {} saturated samples", count)
+    }
+}
+
+/// one shaded rectangle per run of saturated samples
+fn saturated_regions(
+    start: f32,
+    step: f32,
+    values: &[f32],
+    saturated: &[bool],
+) -> Vec<egui::plot::Polygon> {
+    let top = values.iter().copied().fold(1.0, f32::max) as f64;
+    let mut regions = Vec::new();
+    let mut i = 0;
+    while i < saturated.len() {
+        if !saturated[i] {
+            i += 1;
+            continue;
+        }
+        let first = i;
+        while i < saturated.len() && saturated[i] {
+            i += 1;
+        }
+        // cover half a step on either side so single samples are visible
+        let x0 = (start + (first as f32 - 0.5) * step) as f64;
+        let x1 = (start + (i as f32 - 0.5) * step) as f64;
+        regions.push(
+            egui::plot::Polygon::new(PlotPoints::new(vec![
+                [x0, 0.0],
+                [x1, 0.0],
+                [x1, top],
+                [x0, top],
+            ]))
+            .color(egui::Color32::RED)
+            .fill_alpha(0.2)
+            .name("saturated"),
+        );
+    }
+    regions
+}
+
+fn saturation_column(saturated: &[bool]) -> Vec<f32> {
+    saturated
+        .iter()
+        .map(|saturated| if *saturated { 1.0 } else { 0.0 })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_module::synthetic_source::tests::{
+        frame, measure_lines, settings, WAVELENGTHS,
+    };
+
+    /// spectrum of light with `radiance` per second, clipped at full scale
+    fn exposed(radiance: &[f32], time: i64) -> AbsSpectrograph {
+        let exposure = Exposure { time, gain: None };
+        let values: Vec<f32> = radiance
+            .iter()
+            .map(|radiance| (radiance * exposure.seconds()).min(1.0))
+            .collect();
+        AbsSpectrograph {
+            start: 400.0,
+            stop: 400.0 + radiance.len() as f32,
+            step: 1.0,
+            channels: [values.clone(), values.clone(), values.clone()],
+            saturated: values.iter().map(|val| *val >= 1.0).collect(),
+            values,
+            variance: None,
+            channel_variance: None,
+            exposure: Some(exposure),
+            stamp: None,
+        }
+    }
+
+    #[test]
+    fn hdr_merge_recovers_the_clipped_radiance() {
+        let radiance = [10.0, 50.0, 200.0];
+        // the longer exposure clips the second and the third sample, the shorter the third
+        let merged = merge_hdr(&[exposed(&radiance, 400), exposed(&radiance, 100)]);
+        assert!(merged.exposure.is_none());
+        for (merged, radiance) in merged.values[..2].iter().zip(radiance) {
+            assert!((merged / radiance - 1.0).abs() < 1e-5);
+        }
+        for channel in merged.channels.iter() {
+            assert!((channel[1] / radiance[1] - 1.0).abs() < 1e-5);
+        }
+        assert_eq!(merged.saturated, [false, false, true]);
+        // clipped everywhere, so only the lower bound from the shortest exposure is left
+        assert!((merged.values[2] - 100.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn merged_brackets_are_marked_in_the_header() {
+        let radiance = [10.0, 50.0, 200.0];
+        let bracket = [100, 400].map(|time| Exposure { time, gain: None });
+        let mut module = SpectrographModule::default();
+        assert!(module
+            .add_to_bracket(exposed(&radiance, 100), &bracket)
+            .is_none());
+        assert!(module
+            .add_to_bracket(exposed(&radiance, 400), &bracket)
+            .is_some());
+        let header = module.csv_header(None, 0, 0);
+        assert!(header.contains("hdr merged from 2 exposures, intensity per second"));
+    }
+
+    #[test]
+    fn peaks_of_a_synthetic_frame_are_at_the_rendered_wavelengths() {
+        let settings = settings();
+        let img = frame(&settings);
+        let mut calib = CalibrationModule::with_lines(measure_lines(&img));
+        let spectrum = AbsSpectrograph::from_img(
+            &img,
+            &mut calib,
+            400.0,
+            650.0,
+            0.5,
+            &ChannelSettings::default(),
+            &Aperture::default(),
+        )
+        .unwrap();
+        let values = &spectrum.values;
+        let max = values.iter().cloned().fold(0.0, f32::max);
+        let peaks: Vec<f32> = (1..values.len() - 1)
+            .filter(|&i| {
+                values[i] > max / 2.0 && values[i] >= values[i - 1] && values[i] > values[i + 1]
+            })
+            .map(|i| spectrum.start + i as f32 * spectrum.step)
+            .collect();
+        assert_eq!(peaks.len(), WAVELENGTHS.len());
+        for (peak, wavelength) in peaks.iter().zip(WAVELENGTHS) {
+            assert!(
+                (peak - wavelength).abs() <= 1.0,
+                "{} != {}",
+                peak,
+                wavelength
+            );
+        }
+    }
+}
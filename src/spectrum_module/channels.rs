@@ -0,0 +1,346 @@
+use std::fmt;
+
+use egui::{DragValue, Ui};
+
+use crate::{LARGEST_WAVELENGTH, SMALLEST_WAVELENGTH};
+
+/// step in nm with which the response curves are sampled
+const RESPONSE_STEP: f32 = 5.0;
+
+/// How the red, green and blue spectra are reduced to a single intensity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum Combination {
+    /// mean of the three channels, the lightness of the whole pixel
+    #[default]
+    Sum,
+    /// brightest channel at every wavelength
+    Max,
+    Red,
+    Green,
+    Blue,
+    /// estimate from the channel responses at every wavelength, each channel weighted by
+    /// its signal to noise ratio
+    Weighted,
+}
+
+impl Combination {
+    pub const ALL: [Combination; 6] = [
+        Combination::Sum,
+        Combination::Max,
+        Combination::Red,
+        Combination::Green,
+        Combination::Blue,
+        Combination::Weighted,
+    ];
+}
+
+impl fmt::Display for Combination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Combination::Sum => write!(f, "sum"),
+            Combination::Max => write!(f, "max"),
+            Combination::Red => write!(f, "red"),
+            Combination::Green => write!(f, "green"),
+            Combination::Blue => write!(f, "blue"),
+            Combination::Weighted => write!(f, "weighted by response"),
+        }
+    }
+}
+
+/// Relative sensitivity of the red, green and blue channel of the sensor over the wavelength.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ChannelResponse {
+    start: f32,
+    step: f32,
+    /// one rgb triple per step
+    values: Vec<[f32; 3]>,
+    /// estimated from a reference instead of the typical sensor
+    measured: bool,
+}
+
+impl ChannelResponse {
+    /// Estimates the response from the channels of a broadband reference. The spectrum of
+    /// the lamp cancels out between the channels, so the responses at every wavelength are
+    /// the fractions of the channels scaled such that combining the reference gives the
+    /// mean of its channels.
+    pub fn from_reference(start: f32, step: f32, channels: &[Vec<f32>; 3]) -> Self {
+        let values = (0..channels[0].len())
+            .map(|i| {
+                let rgb = [channels[0][i], channels[1][i], channels[2][i]];
+                let total: f32 = rgb.iter().sum();
+                if total > 0.0 {
+                    rgb.map(|val| 3.0 * val.max(0.0) / total)
+                } else {
+                    [1.0; 3]
+                }
+            })
+            .collect();
+        Self {
+            start,
+            step,
+            values,
+            measured: true,
+        }
+    }
+
+    /// response at `wavelength`, interpolated linearly and clamped to the sampled range
+    pub fn at(&self, wavelength: f32) -> [f32; 3] {
+        let last = match self.values.len().checked_sub(1) {
+            Some(last) => last,
+            None => return [1.0; 3],
+        };
+        let pos = ((wavelength - self.start) / self.step).clamp(0.0, last as f32);
+        let i = (pos.floor() as usize).min(last);
+        let next = (i + 1).min(last);
+        let t = pos - i as f32;
+        let mut rgb = [0.0; 3];
+        for (channel, val) in rgb.iter_mut().enumerate() {
+            *val = self.values[i][channel] * (1.0 - t) + self.values[next][channel] * t;
+        }
+        rgb
+    }
+
+    pub fn is_measured(&self) -> bool {
+        self.measured
+    }
+}
+
+impl Default for ChannelResponse {
+    /// Typical colour sensor with a bayer filter, each channel approximated by a gaussian.
+    /// The peaks are only rough guesses, so the weighting is approximate.
+    fn default() -> Self {
+        let gaussian = |wavelength: f32, center: f32, sigma: f32| {
+            (-0.5 * ((wavelength - center) / sigma).powi(2)).exp()
+        };
+        let start = SMALLEST_WAVELENGTH as f32;
+        let count =
+            ((LARGEST_WAVELENGTH - SMALLEST_WAVELENGTH) as f32 / RESPONSE_STEP) as usize + 1;
+        let values = (0..count)
+            .map(|i| {
+                let wavelength = start + i as f32 * RESPONSE_STEP;
+                [
+                    gaussian(wavelength, 600.0, 45.0),
+                    gaussian(wavelength, 535.0, 40.0),
+                    gaussian(wavelength, 460.0, 35.0),
+                ]
+            })
+            .collect();
+        Self {
+            start,
+            step: RESPONSE_STEP,
+            values,
+            measured: false,
+        }
+    }
+}
+
+/// Noise of a sample, made up of the shot noise of the collected electrons and the read noise.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct NoiseModel {
+    /// electrons collected by a photosite at full scale
+    pub full_well: f32,
+    /// standard deviation of the read noise in electrons
+    pub read_noise: f32,
+}
+
+impl NoiseModel {
+    /// variance of a sample with the expected value `val`, both relative to full scale
+    pub fn variance(&self, val: f32) -> f32 {
+        val.max(0.0) / self.full_well + (self.read_noise / self.full_well).powi(2)
+    }
+}
+
+impl Default for NoiseModel {
+    fn default() -> Self {
+        Self {
+            full_well: 10000.0,
+            read_noise: 5.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ChannelSettings {
+    pub combination: Combination,
+    pub response: ChannelResponse,
+    pub noise: NoiseModel,
+}
+
+impl ChannelSettings {
+    /// Intensity at `wavelength` from the `rgb` values there, and its variance if the
+    /// variances of the channels are known.
+    pub fn combine(
+        &self,
+        rgb: [f32; 3],
+        variance: Option<[f32; 3]>,
+        wavelength: f32,
+    ) -> (f32, Option<f32>) {
+        let coefficients = self.coefficients(rgb, variance, wavelength);
+        // unused channels may be missing entirely
+        let used = coefficients.iter().enumerate().filter(|(_, c)| **c != 0.0);
+        let val = used.clone().map(|(channel, c)| c * rgb[channel]).sum();
+        let var = variance.map(|variance| used.map(|(channel, c)| c * c * variance[channel]).sum());
+        (val, var)
+    }
+
+    /// factors with which the channels are summed up
+    fn coefficients(&self, rgb: [f32; 3], variance: Option<[f32; 3]>, wavelength: f32) -> [f32; 3] {
+        let single = |channel: usize| {
+            let mut coefficients = [0.0; 3];
+            coefficients[channel] = 1.0;
+            coefficients
+        };
+        let mean = [1.0 / 3.0; 3];
+        match self.combination {
+            Combination::Sum => mean,
+            Combination::Max => {
+                let mut brightest = 0;
+                for channel in 1..3 {
+                    if rgb[channel] > rgb[brightest] || rgb[brightest].is_nan() {
+                        brightest = channel
+                    }
+                }
+                single(brightest)
+            }
+            Combination::Red => single(0),
+            Combination::Green => single(1),
+            Combination::Blue => single(2),
+            Combination::Weighted => self
+                .weights(rgb, variance, self.response.at(wavelength))
+                .unwrap_or(mean),
+        }
+    }
+
+    /// Minimum variance weights of an unbiased estimate of the intensity. Each channel is
+    /// weighted by its response over its variance. Unless the variances were measured they
+    /// are expected from a first estimate that is optimal if the shot noise dominates.
+    fn weights(
+        &self,
+        rgb: [f32; 3],
+        variance: Option<[f32; 3]>,
+        response: [f32; 3],
+    ) -> Option<[f32; 3]> {
+        let sensitivity: f32 = response.iter().sum();
+        if sensitivity <= 0.0 {
+            return None;
+        }
+        let variance = match variance {
+            Some(variance) => variance,
+            None => {
+                let rough = rgb.iter().sum::<f32>() / sensitivity;
+                response.map(|s| self.noise.variance(s * rough))
+            }
+        };
+        let mut weights = [0.0; 3];
+        for channel in 0..3 {
+            if variance[channel] > 0.0 {
+                weights[channel] = response[channel] / variance[channel];
+            }
+        }
+        let norm: f32 = weights.iter().zip(response).map(|(w, s)| w * s).sum();
+        if norm <= 0.0 {
+            return None;
+        }
+        Some(weights.map(|w| w / norm))
+    }
+
+    /// returns true if the noise model changed
+    pub fn noise_ui(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = false;
+        ui.horizontal(|ui| {
+            changed |= ui
+                .add(
+                    DragValue::new(&mut self.noise.full_well)
+                        .clamp_range(1.0..=1_000_000.0)
+                        .speed(100.0)
+                        .suffix(" e⁻"),
+                )
+                .changed();
+            ui.label("full well");
+        });
+        ui.horizontal(|ui| {
+            changed |= ui
+                .add(
+                    DragValue::new(&mut self.noise.read_noise)
+                        .clamp_range(0.0..=1000.0)
+                        .speed(0.1)
+                        .suffix(" e⁻"),
+                )
+                .changed();
+            ui.label("read noise");
+        });
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(combination: Combination) -> ChannelSettings {
+        ChannelSettings {
+            combination,
+            ..ChannelSettings::default()
+        }
+    }
+
+    #[test]
+    fn simple_combinations_pick_or_average_the_channels() {
+        let rgb = [0.2, 0.6, 0.4];
+        let variance = Some([0.01, 0.02, 0.03]);
+        let combine = |combination| settings(combination).combine(rgb, variance, 500.0);
+        let (sum, sum_variance) = combine(Combination::Sum);
+        assert!((sum - 0.4).abs() < 1e-6);
+        assert!((sum_variance.unwrap() - 0.06 / 9.0).abs() < 1e-6);
+        assert_eq!(combine(Combination::Max), (0.6, Some(0.02)));
+        assert_eq!(combine(Combination::Red), (0.2, Some(0.01)));
+        assert_eq!(combine(Combination::Green), (0.6, Some(0.02)));
+        assert_eq!(combine(Combination::Blue), (0.4, Some(0.03)));
+    }
+
+    #[test]
+    fn missing_channels_are_left_out_unless_used() {
+        let rgb = [f32::NAN, 0.5, 0.3];
+        assert_eq!(
+            settings(Combination::Green).combine(rgb, None, 500.0),
+            (0.5, None)
+        );
+        assert_eq!(
+            settings(Combination::Max).combine(rgb, None, 500.0),
+            (0.5, None)
+        );
+    }
+
+    #[test]
+    fn weighted_combination_is_unbiased() {
+        let settings = settings(Combination::Weighted);
+        let wavelength = 560.0;
+        let response = settings.response.at(wavelength);
+        let intensity = 0.3;
+        let rgb = response.map(|s| s * intensity);
+        let (val, _) = settings.combine(rgb, None, wavelength);
+        assert!((val - intensity).abs() < 1e-5);
+        // with equal variances the estimate is better than any single channel
+        let variance = [1e-4; 3];
+        let (val, var) = settings.combine(rgb, Some(variance), wavelength);
+        assert!((val - intensity).abs() < 1e-5);
+        for (channel, s) in response.iter().enumerate() {
+            assert!(var.unwrap() <= variance[channel] / (s * s));
+        }
+    }
+
+    #[test]
+    fn response_is_measured_from_a_reference() {
+        let channels = [vec![0.6, 0.0], vec![0.3, 0.0], vec![0.3, 0.0]];
+        let response = ChannelResponse::from_reference(500.0, 10.0, &channels);
+        assert!(response.is_measured());
+        assert_eq!(response.at(500.0), [1.5, 0.75, 0.75]);
+        // without light every channel counts the same
+        assert_eq!(response.at(510.0), [1.0; 3]);
+        assert_eq!(response.at(505.0), [1.25, 0.875, 0.875]);
+        // clamped to the sampled range
+        assert_eq!(response.at(400.0), response.at(500.0));
+        assert_eq!(response.at(600.0), response.at(510.0));
+    }
+}
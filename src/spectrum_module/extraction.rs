@@ -0,0 +1,414 @@
+use std::fmt;
+
+use egui::{DragValue, Ui};
+
+use super::channels::NoiseModel;
+use crate::{
+    calibration_module::Line,
+    camera_module::{BandRow, Image, Sampler},
+};
+
+/// How the light of a wavelength is collected from the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum Extraction {
+    /// mean along a single line interpolated at subpixel positions
+    #[default]
+    Line,
+    /// sum over the aperture
+    Box,
+    /// sum over the aperture weighted by the profile of the slit image (Horne 1986)
+    Optimal,
+}
+
+impl Extraction {
+    pub const ALL: [Extraction; 3] = [Extraction::Line, Extraction::Box, Extraction::Optimal];
+}
+
+impl fmt::Display for Extraction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Extraction::Line => write!(f, "single line"),
+            Extraction::Box => write!(f, "box"),
+            Extraction::Optimal => write!(f, "optimal"),
+        }
+    }
+}
+
+/// Band around every wavelength from which the light is collected. It reaches to the
+/// neighbouring wavelengths along the dispersion and over `width` along the slit.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Aperture {
+    pub extraction: Extraction,
+    /// interpolation along a single line
+    pub sampler: Sampler,
+    /// centre of the slit image along the lines, relative to the full frame
+    pub center: f32,
+    /// extent along the lines, relative to the full frame
+    pub width: f32,
+    /// subtract the light in the background strips of the calibration
+    pub subtract_background: bool,
+}
+
+impl Default for Aperture {
+    fn default() -> Self {
+        Self {
+            extraction: Extraction::default(),
+            sampler: Sampler::default(),
+            center: 0.5,
+            width: 1.0,
+            subtract_background: false,
+        }
+    }
+}
+
+/// Light per pixel that did not come through the slit, estimated for one wavelength.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Background {
+    pub level: [f32; 3],
+    /// variance of the estimated level
+    pub variance: [f32; 3],
+}
+
+impl Background {
+    fn channel(background: Option<&Background>, channel: usize) -> (f32, f32) {
+        background
+            .map(|background| (background.level[channel], background.variance[channel]))
+            .unwrap_or((0.0, 0.0))
+    }
+}
+
+/// Mean of every channel within the `strips` of each band between two neighbouring `edges`.
+/// The strips are given by their top and bottom relative to the full frame.
+pub fn estimate_background(
+    img: &Image,
+    edges: &[Line],
+    strips: &[(f32, f32)],
+    noise: &NoiseModel,
+) -> Vec<Background> {
+    edges
+        .windows(2)
+        .map(|pair| {
+            let mut sums = [0.0; 3];
+            let mut weights = [0.0; 3];
+            let mut squared_weights = [0.0; 3];
+            for (top, bottom) in strips {
+                for row in img.read_band(&pair[0], &pair[1], *top, *bottom) {
+                    for channel in 0..3 {
+                        sums[channel] += row.sums[channel];
+                        weights[channel] += row.weights[channel];
+                        squared_weights[channel] += row.squared_weights[channel];
+                    }
+                }
+            }
+            let mut background = Background::default();
+            for channel in 0..3 {
+                if weights[channel] > 0.0 {
+                    let level = sums[channel] / weights[channel];
+                    background.level[channel] = level;
+                    background.variance[channel] = noise.variance(level) * squared_weights[channel]
+                        / (weights[channel] * weights[channel]);
+                }
+            }
+            background
+        })
+        .collect()
+}
+
+/// Channels collected for one wavelength.
+#[derive(Debug, Clone, Copy)]
+pub struct Extracted {
+    pub channels: [f32; 3],
+    pub variance: [f32; 3],
+    pub saturated: bool,
+}
+
+impl Aperture {
+    /// top and bottom of the aperture relative to the full frame
+    pub fn rows(&self) -> (f32, f32) {
+        (
+            (self.center - self.width / 2.0).max(0.0),
+            (self.center + self.width / 2.0).min(1.0),
+        )
+    }
+
+    /// One sample per band between two neighbouring `edges`, with variances from `noise`.
+    /// The `background` of every band is subtracted from each of its pixels.
+    pub fn extract(
+        &self,
+        img: &Image,
+        edges: &[Line],
+        noise: &NoiseModel,
+        background: Option<&[Background]>,
+    ) -> Vec<Extracted> {
+        let (top, bottom) = self.rows();
+        let bands: Vec<Vec<BandRow>> = edges
+            .windows(2)
+            .map(|pair| img.read_band(&pair[0], &pair[1], top, bottom))
+            .collect();
+        let background_of = |band: usize| background.and_then(|background| background.get(band));
+        let profile = match self.extraction {
+            Extraction::Optimal => Some(spatial_profile(&bands, background_of)),
+            _ => None,
+        };
+        bands
+            .iter()
+            .enumerate()
+            .map(|(band, rows)| {
+                let background = background_of(band);
+                let mut channels = [0.0; 3];
+                let mut variance = [0.0; 3];
+                for channel in 0..3 {
+                    let (val, var) = match profile.as_ref() {
+                        Some(profile) => optimal(rows, profile, channel, noise, background),
+                        None => box_sum(rows, channel, noise, background),
+                    };
+                    channels[channel] = val;
+                    variance[channel] = var;
+                }
+                Extracted {
+                    channels,
+                    variance,
+                    saturated: rows.iter().any(|row| row.saturated),
+                }
+            })
+            .collect()
+    }
+
+    /// returns true if the aperture changed
+    pub fn ui(&mut self, ui: &mut Ui) -> bool {
+        let mut changed = false;
+        egui::ComboBox::from_label("extraction")
+            .selected_text(self.extraction.to_string())
+            .show_ui(ui, |ui| {
+                for extraction in Extraction::ALL {
+                    changed |= ui
+                        .selectable_value(&mut self.extraction, extraction, extraction.to_string())
+                        .changed();
+                }
+            });
+        if self.extraction == Extraction::Line {
+            egui::ComboBox::from_label("interpolation")
+                .selected_text(self.sampler.to_string())
+                .show_ui(ui, |ui| {
+                    for sampler in Sampler::ALL {
+                        changed |= ui
+                            .selectable_value(&mut self.sampler, sampler, sampler.to_string())
+                            .changed();
+                    }
+                });
+            return changed;
+        }
+        ui.horizontal(|ui| {
+            changed |= ui
+                .add(
+                    DragValue::new(&mut self.center)
+                        .clamp_range(0.0..=1.0)
+                        .speed(0.005),
+                )
+                .changed();
+            ui.label("centre of the slit");
+        });
+        ui.horizontal(|ui| {
+            changed |= ui
+                .add(
+                    DragValue::new(&mut self.width)
+                        .clamp_range(0.01..=1.0)
+                        .speed(0.005),
+                )
+                .changed();
+            ui.label("width along the slit");
+        });
+        changed
+    }
+
+    /// returns true if the setting changed
+    pub fn background_ui(&mut self, ui: &mut Ui, strips: usize) -> bool {
+        let response = ui
+            .add_enabled(
+                strips > 0,
+                egui::Checkbox::new(&mut self.subtract_background, "subtract background"),
+            )
+            .on_disabled_hover_text("mark background strips in the calibration view first");
+        if strips > 0 && self.subtract_background {
+            ui.label(format!("estimated from {} strips", strips));
+        }
+        response.changed()
+    }
+}
+
+/// variance of the estimated total of a row whose pixels have the mean value `mean`
+fn row_variance(row: &BandRow, channel: usize, mean: f32, noise: &NoiseModel) -> f32 {
+    if row.weights[channel] <= 0.0 {
+        return 0.0;
+    }
+    let scale = row.area / row.weights[channel];
+    scale * scale * row.squared_weights[channel] * noise.variance(mean)
+}
+
+/// sum of all rows without the background and its variance
+fn box_sum(
+    rows: &[BandRow],
+    channel: usize,
+    noise: &NoiseModel,
+    background: Option<&Background>,
+) -> (f32, f32) {
+    let (level, level_variance) = Background::channel(background, channel);
+    let (sum, var, area) = rows.iter().fold((0.0, 0.0, 0.0), |(sum, var, area), row| {
+        // the noise depends on all light that reached the pixels
+        let mean = match row.weights[channel] > 0.0 {
+            true => row.sums[channel] / row.weights[channel],
+            false => 0.0,
+        };
+        (
+            sum + row.total(channel) - level * row.area,
+            var + row_variance(row, channel, mean, noise),
+            area + row.area,
+        )
+    });
+    (sum, var + area * area * level_variance)
+}
+
+/// Fraction of the light in every row of the aperture, taken from all wavelengths and
+/// channels together. Horne fits the profile as a smooth function of the wavelength, but as
+/// the lines already follow the tilt of the slit a single profile is close enough.
+fn spatial_profile<'a>(
+    bands: &[Vec<BandRow>],
+    background_of: impl Fn(usize) -> Option<&'a Background>,
+) -> Vec<f32> {
+    let len = bands.iter().map(|rows| rows.len()).max().unwrap_or(0);
+    let mut profile = vec![0.0; len];
+    for (band, rows) in bands.iter().enumerate() {
+        let background = background_of(band);
+        for (total, row) in profile.iter_mut().zip(rows) {
+            *total += (0..3)
+                .map(|channel| {
+                    row.total(channel) - Background::channel(background, channel).0 * row.area
+                })
+                .sum::<f32>();
+        }
+    }
+    profile.iter_mut().for_each(|val| *val = val.max(0.0));
+    let sum: f32 = profile.iter().sum();
+    if sum > 0.0 {
+        profile.iter_mut().for_each(|val| *val /= sum);
+    }
+    profile
+}
+
+/// Weights every row by its expected share of the light over its variance. The variances
+/// come from the profile and the box sum rather than the row itself, which would bias the
+/// estimate towards rows that are low by chance. Rows without pixels are left out.
+fn optimal(
+    rows: &[BandRow],
+    profile: &[f32],
+    channel: usize,
+    noise: &NoiseModel,
+    background: Option<&Background>,
+) -> (f32, f32) {
+    let (total, box_variance) = box_sum(rows, channel, noise, background);
+    let (level, level_variance) = Background::channel(background, channel);
+    let mut numerator = 0.0;
+    let mut denominator = 0.0;
+    // how strongly an error of the background level enters the estimate
+    let mut background_weight = 0.0;
+    for (row, share) in rows.iter().zip(profile) {
+        if row.weights[channel] <= 0.0 || row.area <= 0.0 || *share <= 0.0 {
+            continue;
+        }
+        let mean = share * total.max(0.0) / row.area + level;
+        let var = row_variance(row, channel, mean, noise);
+        if var <= 0.0 {
+            continue;
+        }
+        numerator += share * (row.total(channel) - level * row.area) / var;
+        denominator += share * share / var;
+        background_weight += share * row.area / var;
+    }
+    match denominator > 0.0 {
+        true => (
+            numerator / denominator,
+            1.0 / denominator + (background_weight / denominator).powi(2) * level_variance,
+        ),
+        false => (total, box_variance),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 16;
+    const HEIGHT: usize = 12;
+
+    /// grey image whose rows have the brightness given by `profile`
+    fn slit_image(profile: impl Fn(usize) -> f32) -> Image {
+        let data = (0..WIDTH * HEIGHT)
+            .flat_map(|i| [(profile(i / WIDTH) * u16::MAX as f32).round() as u16; 3])
+            .collect();
+        Image::new(WIDTH, HEIGHT, data, 16)
+    }
+
+    fn vertical(x: f32) -> Line {
+        Line {
+            start: (x / WIDTH as f32, 0.0),
+            end: (x / WIDTH as f32, 1.0),
+        }
+    }
+
+    fn aperture(extraction: Extraction) -> Aperture {
+        Aperture {
+            extraction,
+            ..Aperture::default()
+        }
+    }
+
+    #[test]
+    fn box_sums_the_covered_fraction_of_the_pixels() {
+        let img = slit_image(|_| 0.5);
+        let edges = [vertical(2.25), vertical(5.75)];
+        let extracted =
+            aperture(Extraction::Box).extract(&img, &edges, &NoiseModel::default(), None);
+        assert_eq!(extracted.len(), 1);
+        let expected = 0.5 * 3.5 * HEIGHT as f32;
+        for val in extracted[0].channels {
+            assert!((val / expected - 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn optimal_extraction_is_unbiased_and_less_noisy() {
+        let profile = |row: usize| 0.05 + 0.8 * (-((row as f32 - 6.0) / 1.5).powi(2)).exp();
+        let img = slit_image(profile);
+        let edges = [vertical(4.0), vertical(6.0), vertical(8.0)];
+        let noise = NoiseModel::default();
+        let boxed = aperture(Extraction::Box).extract(&img, &edges, &noise, None);
+        let optimal = aperture(Extraction::Optimal).extract(&img, &edges, &noise, None);
+        for (boxed, optimal) in boxed.iter().zip(optimal.iter()) {
+            for channel in 0..3 {
+                assert!((optimal.channels[channel] / boxed.channels[channel] - 1.0).abs() < 1e-3);
+                assert!(optimal.variance[channel] < boxed.variance[channel]);
+            }
+        }
+    }
+
+    #[test]
+    fn background_from_the_strips_is_subtracted() {
+        let img = slit_image(|row| if (4..8).contains(&row) { 0.6 } else { 0.1 });
+        let edges = [vertical(4.0), vertical(7.0)];
+        let noise = NoiseModel::default();
+        // the first and the last two rows
+        let strips = [(0.0, 2.0 / HEIGHT as f32), (10.0 / HEIGHT as f32, 1.0)];
+        let background = estimate_background(&img, &edges, &strips, &noise);
+        for level in background[0].level {
+            assert!((level - 0.1).abs() < 1e-4);
+        }
+        for extraction in [Extraction::Box, Extraction::Optimal] {
+            let extracted = aperture(extraction).extract(&img, &edges, &noise, Some(&background));
+            // four rows of three pixels each
+            for val in extracted[0].channels {
+                assert!((val - 0.5 * 3.0 * 4.0).abs() < 1e-2, "{:?}", extraction);
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::{path::PathBuf, time::Instant};
+use std::path::PathBuf;
 
 use egui::{
     plot::{Bar, BarChart, Plot},
@@ -10,13 +10,18 @@ use native_dialog::FileDialog;
 
 use crate::{
     calibration_module::CalibrationModule,
-    camera_module::{CameraStream, Image},
+    camera_module::{frame_buffer::Frame, CameraStream, Image},
     csv, LARGEST_WAVELENGTH, SMALLEST_WAVELENGTH,
 };
 
 pub struct TracerModule {
-    start_inst: Option<std::time::Instant>,
+    /// first frame of the recording, the time axis starts with it
+    start: Option<Frame>,
     time_s: Vec<f32>,
+    /// the last processed frame
+    previous: Option<Frame>,
+    /// frames missed while recording
+    dropped: u64,
     tracers: Vec<PeakTrace>,
     record: bool,
     reconfigure_next: bool,
@@ -32,13 +37,12 @@ impl TracerModule {
         &mut self,
         ctx: &Context,
         calib: &mut CalibrationModule,
-        width: u32,
-        height: u32,
+        stream: &mut CameraStream,
     ) {
         egui::SidePanel::right("tracer_opts").show(ctx, |ui| self.side_panel(ui));
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            self.main_view(ui, calib, width, height);
+            self.main_view(ui, calib, stream);
         });
     }
 }
@@ -48,38 +52,15 @@ impl TracerModule {
         &mut self,
         ui: &mut Ui,
         calib: &mut CalibrationModule,
-        width: u32,
-        height: u32,
+        stream: &mut CameraStream,
     ) {
-        if let Some(img) = CameraStream::get_img(width, height) {
-            // update according to flags
-            if self.record {
-                let t0 = self
-                    .start_inst
-                    .expect("the start value should always be known while recording");
-                self.time_s.push((Instant::now() - t0).as_secs_f32())
-            }
-            for tracer in &mut self.tracers {
-                tracer.update(&img, calib, self.record);
-            }
-            if self.add_new_next {
-                match PeakTrace::new(500.0, &img, calib) {
-                    Some(tracer) => self.tracers.push(tracer),
-                    None => warn!("could not add new tracer"),
-                }
-                self.add_new_next = false
-            }
-            if self.reconfigure_next {
-                self.tracers
-                    .sort_by(|a, b| a.wavelength.partial_cmp(&b.wavelength).unwrap());
-                if self.record {
-                    self.start_recording()
-                } else {
-                    self.take_reference()
-                }
-                self.reconfigure_next = false
-            }
+        let last_sequence = self.previous.as_ref().map(|frame| frame.sequence);
+        for frame in stream.frames_since(last_sequence) {
+            self.update(&frame, calib);
+            self.previous = Some(frame);
+        }
 
+        if self.previous.is_some() {
             if self.record {
                 Plot::new("Tracer plot")
                     .legend(egui::plot::Legend::default())
@@ -101,13 +82,53 @@ impl TracerModule {
                 let chart = BarChart::new(bars).vertical().name("abdbdb");
                 Plot::new("Absorbance").show(ui, |plot_ui| plot_ui.bar_chart(chart));
             }
-            ui.ctx().request_repaint()
         } else {
             ui.strong("could not get image");
         }
+        ui.ctx().request_repaint()
+    }
+
+    fn update(&mut self, frame: &Frame, calib: &mut CalibrationModule) {
+        let img = frame.img.as_ref();
+        // update according to flags
+        if self.record {
+            match self.start.as_ref() {
+                Some(start) => {
+                    self.time_s.push(frame.seconds_since(start));
+                    if let Some(previous) = self.previous.as_ref() {
+                        self.dropped += frame.dropped_since(previous);
+                    }
+                }
+                None => {
+                    self.start = Some(frame.clone());
+                    self.time_s.push(0.0);
+                }
+            }
+        }
+        for tracer in &mut self.tracers {
+            tracer.update(img, calib, self.record);
+        }
+        if self.add_new_next {
+            match PeakTrace::new(500.0, img, calib) {
+                Some(tracer) => self.tracers.push(tracer),
+                None => warn!("could not add new tracer"),
+            }
+            self.add_new_next = false
+        }
+        if self.reconfigure_next {
+            self.tracers
+                .sort_by(|a, b| a.wavelength.partial_cmp(&b.wavelength).unwrap());
+            if self.record {
+                self.start_recording()
+            } else {
+                self.take_reference()
+            }
+            self.reconfigure_next = false
+        }
     }
 
     fn save_current(&mut self) {
+        let header = self.csv_header();
         match self.path.as_mut() {
             Some(path) => {
                 let mut keys = vec!["Time [s]".to_string()];
@@ -118,7 +139,7 @@ impl TracerModule {
                     keys.push(key);
                     valss.push(relative_points);
                 }
-                if let Err(err) = csv::write_f32_csv(path.clone(), keys, valss, &self.comment) {
+                if let Err(err) = csv::write_f32_csv(path.clone(), keys, valss, &header) {
                     error!("failed to save file, Error: {}", err);
                 } else {
                     info!("save file succesfully to {:?}", &path)
@@ -129,6 +150,23 @@ impl TracerModule {
         self.save_next = false;
     }
 
+    /// comment of the user followed by where the time axis comes from and the dropped frames
+    fn csv_header(&self) -> String {
+        let driver_time = self
+            .start
+            .as_ref()
+            .map(|frame| frame.img.stamp.is_some())
+            .unwrap_or(false);
+        let time = match driver_time {
+            true => "time stamps of the camera driver",
+            false => "time of arrival of the frames",
+        };
+        format!(
+            "{}\ntime: {}\ndropped frames: {}",
+            self.comment, time, self.dropped
+        )
+    }
+
     pub fn side_panel(&mut self, ui: &mut Ui) {
         ui.label("trace wavelengths");
         for tracer in &mut self.tracers {
@@ -148,6 +186,9 @@ impl TracerModule {
         if ui.button("start recording").clicked() {
             self.start_recording()
         }
+        if self.record {
+            ui.label(format!("dropped frames: {}", self.dropped));
+        }
 
         if ui.button("save").clicked() {
             let dialog_result = match home::home_dir() {
@@ -177,7 +218,10 @@ impl TracerModule {
 impl TracerModule {
     fn start_recording(&mut self) {
         self.take_reference();
-        self.start_inst = Some(std::time::Instant::now());
+        // the time axis starts with the next frame
+        self.start = None;
+        self.time_s = Vec::new();
+        self.dropped = 0;
         self.record = true;
     }
 
@@ -192,8 +236,10 @@ impl TracerModule {
 impl Default for TracerModule {
     fn default() -> Self {
         Self {
-            start_inst: None,
+            start: None,
             time_s: Vec::new(),
+            previous: None,
+            dropped: 0,
             tracers: Vec::new(),
             record: false,
             reconfigure_next: false,
@@ -215,7 +261,7 @@ pub struct PeakTrace {
 
 impl PeakTrace {
     fn new(wavelength: f32, img: &Image, calib: &mut CalibrationModule) -> Option<Self> {
-        let current_val = img.read_line_lightness(&calib.get_line(wavelength)?);
+        let current_val = img.read_line_lightness(&calib.get_line(wavelength)?)?;
         Some(Self {
             wavelength,
             reference: current_val,
@@ -224,12 +270,18 @@ impl PeakTrace {
         })
     }
 
-    fn update(&mut self, img: &Image, calib: &mut CalibrationModule, record: bool) -> Option<()> {
-        self.current_abs = img.read_line_lightness(&calib.get_line(self.wavelength)?);
+    /// While recording a value is pushed for every frame, so all traces line up with the time
+    /// axis. It is NaN if the line has no usable pixels, e.g. outside the region of interest.
+    fn update(&mut self, img: &Image, calib: &mut CalibrationModule, record: bool) {
+        let val = calib
+            .get_line(self.wavelength)
+            .and_then(|line| img.read_line_lightness(&line));
+        if let Some(val) = val {
+            self.current_abs = val;
+        }
         if record {
-            self.abs_values.push(self.current_abs)
+            self.abs_values.push(val.unwrap_or(f32::NAN))
         }
-        Some(())
     }
 
     fn take_reference(&mut self) {
@@ -264,7 +316,35 @@ impl PeakTrace {
         self.abs_values
             .iter()
             .zip(ts)
+            .filter(|(val, _)| val.is_finite())
             .map(|(val, t)| [*t as f64, (*val / self.reference) as f64])
             .collect_vec()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera_module::synthetic_source::tests::{frame, measure_lines, settings};
+
+    #[test]
+    fn frames_without_data_keep_the_traces_aligned() {
+        let img = frame(&settings());
+        let mut calib = CalibrationModule::with_lines(measure_lines(&img));
+        let mut tracer = PeakTrace::new(546.0, &img, &mut calib).unwrap();
+        let mut masked = img.clone();
+        masked.bad_pixels = Some(vec![true; img.width * img.height]);
+
+        tracer.update(&img, &mut calib, true);
+        tracer.update(&masked, &mut calib, true);
+        tracer.update(&img, &mut calib, true);
+        assert_eq!(tracer.abs_values.len(), 3);
+        assert!(tracer.abs_values[1].is_nan());
+        // the last value is kept as the reference can't be taken from missing data
+        tracer.update(&masked, &mut calib, false);
+        assert!((tracer.current_rel() - 1.0).abs() < 1e-6);
+
+        let points = tracer.make_points(&[0.0, 1.0, 2.0]);
+        assert_eq!(points.iter().map(|[t, _]| *t).collect_vec(), [0.0, 2.0]);
+    }
+}